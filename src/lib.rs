@@ -0,0 +1,7749 @@
+//! Stream a Qubes OS window capture from one qube to another, which encodes,
+//! records and streams it over RTMP.
+//!
+//! The producer sends a video info header followed by raw frames over its
+//! stdout, the receiver reads both from its stdin and answers with control
+//! messages.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufRead, Read, Write};
+use std::num::NonZeroU32;
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use gst::MessageView;
+use gst::glib;
+use gstreamer as gst;
+use gstreamer::glib::object::Cast;
+use gstreamer::prelude::{
+    ElementExt, ElementExtManual, GhostPadExt, GstBinExt, GstBinExtManual, GstObjectExt,
+    GstValueExt, ObjectExt, PadExt, PadExtManual, ToValue,
+};
+use gstreamer_video as gst_video;
+use nix::fcntl::{FcntlArg, OFlag, fcntl};
+use nix::sys::resource::{UsageWho, getrusage};
+use nix::sys::signal::Signal;
+use serde::Deserialize;
+
+#[derive(Args)]
+pub struct TestRtmpArgs {
+    server: String,
+
+    /// Read the stream key from this file
+    #[arg(
+        long,
+        value_name = "PATH",
+        required_unless_present = "twitch_key_prompt",
+        conflicts_with = "twitch_key_prompt"
+    )]
+    twitch_key_file: Option<PathBuf>,
+
+    /// Prompt for the stream key on the terminal
+    #[arg(long)]
+    twitch_key_prompt: bool,
+
+    /// Seconds to stream the test pattern for
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    duration: u64,
+
+    /// Connect over plain, unencrypted RTMP instead of RTMPS
+    #[arg(long)]
+    insecure_rtmp: bool,
+
+    /// RTMP application path between the server and the key, empty for none
+    #[arg(long, default_value = "app")]
+    rtmp_app: String,
+}
+
+#[derive(Args)]
+pub struct BenchArgs {
+    /// Video codec to benchmark, with the encoder the receiver would pick
+    #[arg(long, value_enum, default_value_t = Codec::H264)]
+    codec: Codec,
+
+    /// Resolutions to encode, comma separated
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_resolution,
+        default_values = ["1280x720", "1920x1080", "2560x1440"]
+    )]
+    resolutions: Vec<Resolution>,
+
+    /// Bitrates to encode each resolution at, in kbit/s, comma separated
+    #[arg(long, value_delimiter = ',', default_values_t = [3000u32, 6000])]
+    bitrates: Vec<u32>,
+
+    /// Framerate of the test pattern, as `FPS` or `NUM/DEN`
+    #[arg(long, default_value = "30", value_parser = parse_framerate)]
+    framerate: gst::Fraction,
+
+    /// Seconds to encode each combination for
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    duration: u64,
+}
+
+#[derive(Args)]
+pub struct ProduceArgs {
+    /// Framerate sent to the receiver when the capture source doesn't report a
+    /// fixed one, and of the composited video with --pip
+    #[arg(long, default_value_t = 25, value_parser = clap::value_parser!(i32).range(1..))]
+    fallback_framerate: i32,
+
+    /// Seconds to wait for EOS to drain the pipeline on shutdown before forcing it to stop
+    #[arg(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+
+    /// Seconds to wait for the first frame when probing the video info at startup
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    probe_timeout: u64,
+
+    /// Build and link the pipeline, bring it to Ready and stop, without streaming
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the pipeline as a gst-launch-1.0 command line on stderr before
+    /// running it, with --dry-run to only print it
+    #[arg(long)]
+    print_pipeline: bool,
+
+    /// Write a graphviz DOT graph of the pipeline into this directory once
+    /// it plays and when it fails
+    #[arg(long, value_name = "DIR")]
+    dump_dot: Option<PathBuf>,
+
+    /// Warn when no control message (e.g. a heartbeat) arrives from the receiver
+    /// within this many seconds, 0 disables the check
+    #[arg(long, default_value_t = 0)]
+    heartbeat_timeout: u64,
+
+    /// Also write the video info header and the raw video to this file, to
+    /// replay the session later with `receive --input-file`
+    #[arg(long, value_name = "PATH")]
+    dump: Option<PathBuf>,
+
+    /// File descriptor the video info header and the raw video are written to
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(i32).range(0..))]
+    fd: RawFd,
+
+    /// Send a 640x480 test pattern instead of capturing the display, to run
+    /// both ends headlessly
+    #[arg(long, hide = true)]
+    test_source: bool,
+
+    /// What to capture: x11 for the display, or v4l2:DEVICE for a camera such
+    /// as v4l2:/dev/video0 [default: x11]
+    #[arg(long, value_name = "SOURCE", value_parser = parse_capture_source, conflicts_with = "test_source")]
+    source: Option<CaptureSource>,
+
+    /// Composite this camera, e.g. /dev/video0, as an inset over the display capture
+    #[arg(long, value_name = "DEVICE", conflicts_with_all = ["source", "test_source"])]
+    pip: Option<PathBuf>,
+
+    /// Corner of the display the --pip inset goes in
+    #[arg(long, value_enum, default_value_t = OverlayPosition::BottomRight, requires = "pip")]
+    pip_position: OverlayPosition,
+
+    /// Box the --pip inset is scaled to fit in, keeping its aspect ratio
+    #[arg(long, value_name = "WIDTHxHEIGHT", default_value = "320x240", value_parser = parse_resolution, requires = "pip")]
+    pip_size: Resolution,
+
+    /// Capture only the X window with this id, in decimal or 0x hex as printed
+    /// by xwininfo
+    #[arg(long, value_parser = parse_xid, conflicts_with_all = ["window_name", "test_source"])]
+    xid: Option<u64>,
+
+    /// Capture only the X window with this name
+    #[arg(long, conflicts_with = "test_source")]
+    window_name: Option<String>,
+
+    /// Capture only this rectangle of the display, given as X,Y,WIDTH,HEIGHT in pixels
+    #[arg(
+        long,
+        value_name = "X,Y,WIDTH,HEIGHT",
+        value_parser = parse_region,
+        conflicts_with_all = ["xid", "window_name", "test_source"]
+    )]
+    region: Option<Region>,
+
+    /// Capture only this monitor, by index or output name as listed by
+    /// `xrandr --listmonitors`
+    #[arg(long, conflicts_with_all = ["xid", "window_name", "region", "test_source"])]
+    monitor: Option<String>,
+
+    /// X display to capture, e.g. :1 [default: $DISPLAY]
+    #[arg(long, conflicts_with = "test_source")]
+    display: Option<String>,
+
+    /// Only grab the parts of the display that changed, saving CPU on mostly
+    /// static screens
+    #[arg(long, conflicts_with = "test_source")]
+    use_damage: bool,
+
+    /// Draw the mouse pointer in the capture [default: ximagesrc default]
+    #[arg(long, overrides_with = "no_show_pointer")]
+    show_pointer: bool,
+
+    /// Leave the mouse pointer out of the capture
+    #[arg(long, overrides_with = "show_pointer")]
+    no_show_pointer: bool,
+}
+
+impl ProduceArgs {
+    /// Rectangle of the display to capture, given directly or from the monitor
+    fn capture_region(&self) -> anyhow::Result<Option<Region>> {
+        match &self.monitor {
+            Some(monitor) => {
+                let region = monitor_region(self.display.as_deref(), monitor)?;
+                tracing::info!(%monitor, ?region, "capturing monitor");
+
+                Ok(Some(region))
+            }
+            None => Ok(self.region),
+        }
+    }
+
+    /// Camera to capture instead of the display, if any
+    fn v4l2_device(&self) -> Option<&Path> {
+        match &self.source {
+            Some(CaptureSource::V4l2(device)) => Some(device),
+            _ => None,
+        }
+    }
+
+    /// Whether to draw the pointer, when either flag was given
+    fn show_pointer(&self) -> Option<bool> {
+        match (self.show_pointer, self.no_show_pointer) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// Device the producer captures video from
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum CaptureSource {
+    X11,
+    V4l2(PathBuf),
+}
+
+fn parse_capture_source(value: &str) -> Result<CaptureSource, String> {
+    match value.split_once(':') {
+        None if value == "x11" => Ok(CaptureSource::X11),
+        None if value == "v4l2" => Ok(CaptureSource::V4l2("/dev/video0".into())),
+        Some(("v4l2", device)) if !device.is_empty() => Ok(CaptureSource::V4l2(device.into())),
+        _ => Err("expected x11 or v4l2:DEVICE".into()),
+    }
+}
+
+/// Rectangle of the display to capture
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+fn parse_region(value: &str) -> Result<Region, String> {
+    let parts = value
+        .split(',')
+        .map(|part| part.trim().parse::<u32>().map_err(|err| err.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let [x, y, width, height] = parts[..] else {
+        return Err("expected X,Y,WIDTH,HEIGHT".into());
+    };
+
+    if width == 0 || height == 0 {
+        return Err("width and height must be positive".into());
+    }
+
+    x.checked_add(width)
+        .zip(y.checked_add(height))
+        .ok_or("region out of range")?;
+
+    Ok(Region {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
+/// Look up the region of `monitor` on `display` with xrandr
+fn monitor_region(display: Option<&str>, monitor: &str) -> anyhow::Result<Region> {
+    let mut command = std::process::Command::new("xrandr");
+    if let Some(display) = display {
+        command.args(["--display", display]);
+    }
+
+    let output = command
+        .arg("--listmonitors")
+        .output()
+        .context("running xrandr to look up the monitor")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "xrandr failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    let listing = String::from_utf8_lossy(&output.stdout);
+    parse_monitor_region(&listing, monitor)
+        .with_context(|| format!("monitor {} not found in:\n{}", monitor, listing.trim()))
+}
+
+/// Find `monitor`, by index or name, in the output of `xrandr --listmonitors`,
+/// whose lines look like ` 0: +*DP-1 2560/597x1440/336+0+0  DP-1`
+fn parse_monitor_region(listing: &str, monitor: &str) -> Option<Region> {
+    listing.lines().skip(1).find_map(|line| {
+        let (index, rest) = line.trim().split_once(':')?;
+        let fields = rest.split_whitespace().collect::<Vec<_>>();
+        let [_, geometry, .., name] = fields[..] else {
+            return None;
+        };
+        if index != monitor && name != monitor {
+            return None;
+        }
+
+        // WIDTH/MMxHEIGHT/MM+X+Y
+        let (width, rest) = geometry.split_once('/')?;
+        let (_, rest) = rest.split_once('x')?;
+        let (height, rest) = rest.split_once('/')?;
+        let mut offsets = rest.split('+').skip(1);
+
+        Some(Region {
+            x: offsets.next()?.parse().ok()?,
+            y: offsets.next()?.parse().ok()?,
+            width: width.parse().ok()?,
+            height: height.parse().ok()?,
+        })
+    })
+}
+
+fn parse_xid(value: &str) -> Result<u64, String> {
+    let xid = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    xid.map_err(|err| err.to_string())
+}
+
+#[derive(Args)]
+pub struct ReceiveArgs {
+    #[arg(required_unless_present = "local_only")]
+    twitch_server: Option<String>,
+
+    /// Twitch stream key, visible to other processes and in logs of the command
+    /// line, prefer --twitch-key-file or --twitch-key-prompt
+    #[arg(required_unless_present_any = [
+        "local_only",
+        "twitch_key_file",
+        "twitch_key_prompt",
+        "rtmp_stream_key",
+    ])]
+    twitch_key: Option<String>,
+
+    /// Stream key, the same as the positional key for ingests other than Twitch
+    #[arg(
+        long,
+        value_name = "KEY",
+        conflicts_with_all = ["twitch_key", "twitch_key_file", "twitch_key_prompt"]
+    )]
+    rtmp_stream_key: Option<String>,
+
+    /// RTMP application path between the server and the stream key, e.g.
+    /// live2 for YouTube, empty for none
+    #[arg(long, default_value = "app", requires = "twitch_server")]
+    rtmp_app: String,
+
+    /// Read the Twitch stream key from this file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["twitch_key", "twitch_key_prompt"])]
+    twitch_key_file: Option<PathBuf>,
+
+    /// Prompt for the Twitch stream key on the terminal, as stdin carries the video
+    #[arg(long, conflicts_with = "twitch_key")]
+    twitch_key_prompt: bool,
+
+    /// Connect to the Twitch server over plain, unencrypted RTMP instead of RTMPS,
+    /// e.g. for a self-hosted ingest
+    #[arg(long, requires = "twitch_server")]
+    insecure_rtmp: bool,
+
+    /// Only record locally, without streaming anywhere
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "twitch_server",
+            "twitch_key",
+            "twitch_key_file",
+            "twitch_key_prompt",
+            "rtmp_stream_key",
+            "rtmp_urls",
+        ]
+    )]
+    local_only: bool,
+
+    /// Also stream to this RTMP(S) URL, may be repeated to multistream
+    #[arg(long = "rtmp-url", value_name = "URL", value_parser = parse_rtmp_url)]
+    rtmp_urls: Vec<String>,
+
+    /// Video codec used to encode the stream
+    #[arg(long, value_enum, default_value_t = Codec::H264)]
+    codec: Codec,
+
+    /// Seconds to wait for EOS to drain the pipeline on shutdown before forcing it to stop
+    #[arg(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+
+    /// Seconds between streaming stats reports (fps, bitrate, queue levels), 0 disables them
+    #[arg(long, default_value_t = 0)]
+    stats_interval: u64,
+
+    /// Also append the stats as line-delimited JSON to this file, or send them
+    /// to it if it's a unix socket
+    #[arg(long, value_name = "PATH", requires = "stats_interval")]
+    stats_json: Option<PathBuf>,
+
+    /// Container of the local recording [default: flv for h264, mkv for h265]
+    #[arg(long, value_enum)]
+    record_format: Option<RecordFormat>,
+
+    /// Continue the recording in a new file after this many seconds, split at
+    /// the next keyframe
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    record_max_duration: Option<u64>,
+
+    /// Continue the recording in a new file once it reaches this many MB, split
+    /// at the next keyframe
+    #[arg(long, value_name = "MB", value_parser = clap::value_parser!(u64).range(1..))]
+    record_max_size: Option<u64>,
+
+    /// Write the FLV recording as streamable, without seeking back to fill in
+    /// the duration and index, which makes the file harder to seek in
+    #[arg(long)]
+    record_flv_streamable: bool,
+
+    /// Start streaming without recording, until SIGHUP starts the recording.
+    /// SIGHUP toggles the recording either way
+    #[arg(long, conflicts_with = "local_only")]
+    defer_recording: bool,
+
+    /// Milliseconds the recording muxer waits for late inputs before
+    /// interleaving without them, flv and mp4 only
+    #[arg(long, value_name = "MS")]
+    record_mux_latency: Option<u64>,
+
+    /// Milliseconds the RTMP muxer waits for late inputs before interleaving
+    /// without them
+    #[arg(long, value_name = "MS")]
+    rtmp_mux_latency: Option<u64>,
+
+    /// Also write an HLS playlist and segments into this directory, for serving over HTTP
+    #[arg(long)]
+    hls_dir: Option<PathBuf>,
+
+    /// Target duration of each HLS segment, in seconds
+    #[arg(long, default_value_t = 5, requires = "hls_dir")]
+    hls_segment_duration: u32,
+
+    /// Number of HLS segments kept in the playlist and on disk
+    #[arg(long, default_value_t = 5, requires = "hls_dir")]
+    hls_max_segments: u32,
+
+    /// Pass audio straight through, without the low-pass filter and equalizer
+    #[arg(long)]
+    no_audio_filters: bool,
+
+    /// Gains of the 10 equalizer bands in dB, comma separated, from 29Hz to 15kHz
+    #[arg(
+        long,
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        conflicts_with = "no_audio_filters"
+    )]
+    eq_bands: Vec<f64>,
+
+    /// Record a test tone instead of the default PulseAudio source, to run
+    /// both ends headlessly
+    #[arg(long, hide = true)]
+    test_source: bool,
+
+    /// Times to retry connecting to PulseAudio at startup, as it may come up
+    /// after the receiver
+    #[arg(long, default_value_t = 5)]
+    pulse_retries: u32,
+
+    /// Seconds to wait between PulseAudio connection attempts
+    #[arg(long, value_name = "SECS", default_value_t = 2)]
+    pulse_retry_delay: u64,
+
+    /// Stream silence instead of failing when PulseAudio never comes up
+    #[arg(long)]
+    silent_audio_fallback: bool,
+
+    /// Audio sample rate in Hz
+    #[arg(long, default_value_t = 48000)]
+    audio_rate: i32,
+
+    /// Number of audio channels, FLV only carries mono or stereo AAC
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(i32).range(1..=2))]
+    audio_channels: i32,
+
+    /// Cutoff frequency of the audio low-pass filter in Hz, at most half the audio rate
+    #[arg(long, default_value_t = 20000.0, conflicts_with = "no_audio_filters")]
+    audio_lowpass_cutoff: f32,
+
+    /// Number of poles of the audio low-pass filter, more poles give a steeper slope
+    #[arg(
+        long,
+        default_value_t = 4,
+        value_parser = parse_lowpass_poles,
+        conflicts_with = "no_audio_filters"
+    )]
+    audio_lowpass_poles: i32,
+
+    /// Encoding settings for a common target, the individual options below
+    /// override its values
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+
+    /// Target video bitrate, in kbit/s [default: encoder default]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    bitrate: Option<u32>,
+
+    /// Constrain the H.264 profile, for players that only decode baseline or main
+    #[arg(long, value_enum)]
+    h264_profile: Option<H264Profile>,
+
+    /// Constrain the H.264 level, for players and ingests limited in the frame
+    /// size, framerate and bitrate they decode. Only warns when the video exceeds it
+    #[arg(long, value_enum)]
+    h264_level: Option<H264Level>,
+
+    /// Colorimetry the video is converted to and tagged with before encoding.
+    /// Players pick the YUV to RGB conversion from it and --color-range, a
+    /// mismatch with the actual video shows as tinted, washed-out or crushed colors
+    #[arg(long, value_enum, default_value_t = Colorimetry::Bt709)]
+    colorimetry: Colorimetry,
+
+    /// Range of the encoded values, combined with --colorimetry. Screen content
+    /// is full range, but players assume limited range unless the stream says
+    /// otherwise, and not every encoder says so (openh264enc doesn't), nor does
+    /// every player listen. Only use full range for players known to honor it
+    #[arg(long, value_enum, default_value_t = ColorRange::Limited)]
+    color_range: ColorRange,
+
+    /// Colorimetry of the raw video, as a GStreamer colorimetry string like
+    /// sRGB or bt709 [default: what the producer reports]
+    #[arg(long, value_name = "COLORIMETRY")]
+    input_colorimetry: Option<String>,
+
+    /// Dithering when converting to the encoder format, floyd-steinberg bands
+    /// the least on gradients but costs noticeably more CPU [default: bayer]
+    #[arg(long, value_enum)]
+    convert_dither: Option<ConvertDither>,
+
+    /// Resampling of the chroma planes when converting, lanczos is sharpest on
+    /// text but several times slower [default: linear]
+    #[arg(long, value_enum)]
+    convert_chroma_resampler: Option<ConvertResampler>,
+
+    /// Resampling when converting, slower methods may not keep up with 1080p60
+    /// on a qube's CPU [default: nearest]
+    #[arg(long, value_enum)]
+    convert_method: Option<ConvertResampler>,
+
+    /// Scale the video to fit WIDTHxHEIGHT before encoding, keeping its aspect
+    /// ratio, e.g. 1920x1080 for Twitch [default: producer resolution]
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_resolution)]
+    output_resolution: Option<Resolution>,
+
+    /// Pad the scaled video with black borders to exactly the output resolution
+    #[arg(long)]
+    letterbox: bool,
+
+    /// Burn this text into the video, e.g. a label for the recording
+    #[arg(long, value_name = "TEXT")]
+    overlay_text: Option<String>,
+
+    /// Where --overlay-text goes
+    #[arg(long, value_enum, default_value_t = OverlayPosition::BottomLeft)]
+    overlay_text_position: OverlayPosition,
+
+    /// Burn the wall clock time into the video
+    #[arg(long)]
+    overlay_clock: bool,
+
+    /// Where --overlay-clock goes
+    #[arg(long, value_enum, default_value_t = OverlayPosition::TopRight)]
+    overlay_clock_position: OverlayPosition,
+
+    /// Font size of the overlays, in points
+    #[arg(long, default_value_t = 18, value_parser = clap::value_parser!(u32).range(1..))]
+    overlay_font_size: u32,
+
+    /// Output framerate, as FPS or NUM/DEN, frames are duplicated or dropped to
+    /// hold it exactly [default: producer framerate]
+    #[arg(long, value_parser = parse_framerate)]
+    framerate: Option<gst::Fraction>,
+
+    /// Shift audio against video, in milliseconds: positive delays the audio,
+    /// negative delays the video, e.g. -150 when the audio lags the video by 150ms
+    #[arg(long, default_value_t = 0, allow_hyphen_values = true)]
+    av_offset: i64,
+
+    /// Seconds between keyframes, Twitch recommends 2 [default: encoder default]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    keyframe_interval: Option<u32>,
+
+    /// Encoder rate control, Twitch ingest prefers cbr [default: encoder default]
+    #[arg(long, value_enum)]
+    rate_control: Option<RateControl>,
+
+    /// Complexity of the software H.264 encoder, lower it when the CPU can't keep
+    /// up [default: high, low with --low-latency]
+    #[arg(long, value_enum)]
+    sw_complexity: Option<SwComplexity>,
+
+    /// Content the software H.264 encoder tunes for [default: screen]
+    #[arg(long, value_enum)]
+    sw_usage_type: Option<SwUsageType>,
+
+    /// Speed/quality preset of the selected encoder, e.g. hq for nvh264enc or
+    /// veryfast for x265enc, overriding --low-latency
+    #[arg(long, value_name = "PRESET")]
+    encoder_preset: Option<String>,
+
+    /// Tuning of the selected encoder, e.g. zerolatency for x265enc
+    #[arg(long, value_name = "TUNE")]
+    encoder_tune: Option<String>,
+
+    /// B-frames between reference frames, each delays the output by a frame.
+    /// openh264enc never emits any [default: encoder default, 0 with --low-latency]
+    #[arg(long)]
+    bframes: Option<u32>,
+
+    /// Frames the encoder looks ahead for rate control, delaying the output
+    /// by as many [default: encoder default, 0 with --low-latency]
+    #[arg(long)]
+    rc_lookahead: Option<u32>,
+
+    /// Have the encoder output each frame as soon as it's encoded
+    #[arg(long)]
+    zerolatency: bool,
+
+    /// Lower the encoder bitrate while the RTMP upload falls behind, and raise
+    /// it back once it catches up
+    #[arg(long)]
+    adaptive_bitrate: bool,
+
+    /// Lowest bitrate the adaptive mode may pick, in kbit/s
+    #[arg(long, default_value_t = 1000, requires = "adaptive_bitrate")]
+    min_bitrate: u32,
+
+    /// Highest bitrate the adaptive mode may pick, in kbit/s [default: encoder bitrate]
+    #[arg(long, requires = "adaptive_bitrate")]
+    max_bitrate: Option<u32>,
+
+    /// Build and link the pipeline, bring it to Ready and stop, without streaming
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the pipeline as a gst-launch-1.0 command line on stderr before
+    /// running it, with --dry-run to only print it
+    #[arg(long)]
+    print_pipeline: bool,
+
+    /// Write a graphviz DOT graph of the pipeline into this directory once
+    /// it plays and when it fails
+    #[arg(long, value_name = "DIR")]
+    dump_dot: Option<PathBuf>,
+
+    /// Seconds between heartbeats sent to the producer, 0 disables them
+    #[arg(long, default_value_t = 0)]
+    heartbeat_interval: u64,
+
+    /// Exit with an error if the encoder outputs nothing for this many seconds,
+    /// so a supervisor can restart the stream, 0 disables the watchdog
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    stall_timeout: u64,
+
+    /// Stop streaming cleanly after this many seconds, as on SIGTERM
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    duration: Option<u64>,
+
+    /// Replay a recorded producer session from this file, the video info header
+    /// followed by the raw video, instead of reading it from stdin
+    #[arg(long, value_name = "PATH")]
+    input_file: Option<PathBuf>,
+
+    /// When the raw video ends, as when the producer crashed, wait this many
+    /// seconds for a restarted producer to send a new header and resume with
+    /// it, 0 ends the stream right away
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 0,
+        conflicts_with = "input_file"
+    )]
+    producer_restart_timeout: u64,
+
+    /// File descriptor the video info header and the raw video are read from
+    #[arg(
+        long,
+        default_value_t = 0,
+        value_parser = clap::value_parser!(i32).range(0..),
+        conflicts_with = "input_file"
+    )]
+    fd: RawFd,
+
+    /// Treat the raw video as a live source, timestamped on arrival: frames are
+    /// dropped rather than delayed when falling behind, keeping latency low
+    /// [default: yes, no with --input-file]
+    #[arg(long, overrides_with = "no_live")]
+    live: bool,
+
+    /// Treat the raw video as a recording, timestamped from its framerate:
+    /// every frame is kept, but it runs as fast as it arrives and latency grows
+    /// whenever the pipeline falls behind
+    #[arg(long, overrides_with = "live")]
+    no_live: bool,
+
+    /// Run the whole pipeline on the system clock the live raw video is
+    /// timestamped by, with the audio capture slaved to it and kept
+    /// continuous, so audio and video don't drift apart over long streams
+    #[arg(long, conflicts_with = "no_live")]
+    audio_sync_to_video: bool,
+
+    #[command(flatten)]
+    queue: QueueArgs,
+
+    /// Minimize latency: small leaky queues, no B-frames or lookahead, fast encoder
+    /// presets and 1s keyframes, unless the individual options say otherwise
+    #[arg(long)]
+    low_latency: bool,
+}
+
+impl ReceiveArgs {
+    /// Whether the raw video is live, a replayed file isn't unless asked to
+    fn live(&self) -> bool {
+        match (self.live, self.no_live) {
+            (true, _) => true,
+            (_, true) => false,
+            _ => self.input_file.is_none(),
+        }
+    }
+
+    /// Encoding settings from the preset, if any, overridden by the individual options
+    fn encoding_settings(&self) -> EncodingSettings {
+        let preset = self.preset.map(Preset::settings).unwrap_or_default();
+
+        EncodingSettings {
+            output_resolution: self.output_resolution.or(preset.output_resolution),
+            framerate: self.framerate.or(preset.framerate),
+            bitrate: self.bitrate.or(preset.bitrate),
+            keyframe_interval: self.keyframe_interval.or(preset.keyframe_interval),
+            // presets target H.264, the profile means nothing to other codecs
+            h264_profile: self.h264_profile.or(match self.codec {
+                Codec::H264 => preset.h264_profile,
+                _ => None,
+            }),
+            rate_control: self.rate_control.or(preset.rate_control),
+        }
+    }
+}
+
+/// Encoding parameters shaping the stream, unset ones are left to the
+/// producer or the encoder
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct EncodingSettings {
+    output_resolution: Option<Resolution>,
+    framerate: Option<gst::Fraction>,
+    /// In kbit/s
+    bitrate: Option<u32>,
+    /// In seconds
+    keyframe_interval: Option<u32>,
+    h264_profile: Option<H264Profile>,
+    rate_control: Option<RateControl>,
+}
+
+/// Common streaming targets, following the ingest recommendations
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Preset {
+    /// 1920x1080 at 60fps, 6000kbit/s CBR, high profile
+    #[value(name = "twitch-1080p60")]
+    Twitch1080p60,
+    /// 1280x720 at 60fps, 4500kbit/s CBR, main profile
+    #[value(name = "twitch-720p60")]
+    Twitch720p60,
+    /// 1280x720 at 30fps, 3000kbit/s CBR, main profile
+    #[value(name = "twitch-720p30")]
+    Twitch720p30,
+    /// 1920x1080 at 30fps, 6000kbit/s CBR, high profile
+    #[value(name = "youtube-1080p30")]
+    Youtube1080p30,
+    /// 1280x720 at 30fps, 4000kbit/s CBR, main profile
+    #[value(name = "youtube-720p30")]
+    Youtube720p30,
+}
+
+impl Preset {
+    /// All presets stream CBR with 2s keyframes, as both ingests ask for
+    fn settings(self) -> EncodingSettings {
+        let (width, height, fps, bitrate, h264_profile) = match self {
+            Preset::Twitch1080p60 => (1920, 1080, 60, 6000, H264Profile::High),
+            Preset::Twitch720p60 => (1280, 720, 60, 4500, H264Profile::Main),
+            Preset::Twitch720p30 => (1280, 720, 30, 3000, H264Profile::Main),
+            Preset::Youtube1080p30 => (1920, 1080, 30, 6000, H264Profile::High),
+            Preset::Youtube720p30 => (1280, 720, 30, 4000, H264Profile::Main),
+        };
+
+        EncodingSettings {
+            output_resolution: Some(Resolution { width, height }),
+            framerate: Some(gst::Fraction::new(fps, 1)),
+            bitrate: Some(bitrate),
+            keyframe_interval: Some(2),
+            h264_profile: Some(h264_profile),
+            rate_control: Some(RateControl::Cbr),
+        }
+    }
+}
+
+/// `audiocheblimit` takes an even number of poles between 2 and 32
+fn parse_lowpass_poles(value: &str) -> Result<i32, String> {
+    let poles: i32 = value
+        .parse()
+        .map_err(|err: std::num::ParseIntError| err.to_string())?;
+
+    if !(2..=32).contains(&poles) || poles % 2 != 0 {
+        return Err("must be an even number between 2 and 32".into());
+    }
+
+    Ok(poles)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Resolution {
+    width: i32,
+    height: i32,
+}
+
+impl Resolution {
+    /// Largest resolution within `self` with the aspect ratio of `width`x`height`,
+    /// rounded down to even dimensions as chroma subsampling requires
+    fn fit(self, width: i32, height: i32) -> Resolution {
+        let (width, height) = (i64::from(width), i64::from(height));
+        let (max_width, max_height) = (i64::from(self.width), i64::from(self.height));
+
+        let (fit_width, fit_height) = if max_width * height <= max_height * width {
+            (max_width, max_width * height / width)
+        } else {
+            (max_height * width / height, max_height)
+        };
+
+        Resolution {
+            width: (fit_width as i32 & !1).max(2),
+            height: (fit_height as i32 & !1).max(2),
+        }
+    }
+}
+
+impl std::fmt::Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// Parse a `WIDTHxHEIGHT` resolution
+fn parse_resolution(value: &str) -> Result<Resolution, String> {
+    let (width, height) = value
+        .split_once('x')
+        .ok_or("expected WIDTHxHEIGHT, e.g. 1920x1080")?;
+
+    let parse = |dimension: &str| match dimension.parse::<i32>() {
+        Ok(dimension) if dimension >= 2 => Ok(dimension),
+        Ok(_) => Err("dimensions must be at least 2".to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+
+    Ok(Resolution {
+        width: parse(width)?,
+        height: parse(height)?,
+    })
+}
+
+/// Parse a framerate given as `FPS` or `NUM/DEN`, e.g. 30 or 30000/1001
+fn parse_framerate(value: &str) -> Result<gst::Fraction, String> {
+    let (numer, denom) = value.split_once('/').unwrap_or((value, "1"));
+
+    let parse = |part: &str| match part.parse::<i32>() {
+        Ok(part) if part > 0 => Ok(part),
+        Ok(_) => Err("framerate must be positive".to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+
+    Ok(gst::Fraction::new(parse(numer)?, parse(denom)?))
+}
+
+/// The Twitch stream key, from wherever it was given
+fn read_twitch_key(args: &ReceiveArgs) -> anyhow::Result<Option<String>> {
+    if let Some(key) = read_secret_key(args.twitch_key_file.as_deref(), args.twitch_key_prompt)? {
+        return Ok(Some(key));
+    }
+
+    Ok(args
+        .twitch_key
+        .clone()
+        .or_else(|| args.rtmp_stream_key.clone()))
+}
+
+/// The stream key from `file`, or prompted for when `prompt` is set, none
+/// when it's given neither way
+fn read_secret_key(file: Option<&Path>, prompt: bool) -> anyhow::Result<Option<String>> {
+    if let Some(path) = file {
+        let key = std::fs::read_to_string(path)
+            .with_context(|| format!("reading Twitch key from {}", path.display()))?;
+        let key = key.trim();
+        anyhow::ensure!(
+            !key.is_empty(),
+            "Twitch key file {} is empty",
+            path.display()
+        );
+
+        return Ok(Some(key.into()));
+    }
+
+    if prompt {
+        return prompt_secret("Twitch stream key: ").map(Some);
+    }
+
+    Ok(None)
+}
+
+/// Read a line from the controlling terminal without echoing it
+fn prompt_secret(prompt: &str) -> anyhow::Result<String> {
+    use nix::sys::termios::{self, LocalFlags, SetArg};
+
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("opening the terminal to prompt for the stream key")?;
+
+    let original = termios::tcgetattr(&tty).context("reading terminal attributes")?;
+    let mut silent = original.clone();
+    silent.local_flags.remove(LocalFlags::ECHO);
+    termios::tcsetattr(&tty, SetArg::TCSANOW, &silent).context("disabling terminal echo")?;
+
+    let mut line = String::new();
+    let result = tty
+        .write_all(prompt.as_bytes())
+        .and_then(|()| std::io::BufReader::new(&tty).read_line(&mut line));
+
+    let _ = termios::tcsetattr(&tty, SetArg::TCSANOW, &original);
+    let _ = tty.write_all(b"\n");
+    result.context("reading the stream key")?;
+
+    let secret = line.trim();
+    anyhow::ensure!(!secret.is_empty(), "no stream key entered");
+
+    Ok(secret.into())
+}
+
+/// Limits of the raw video queues, trading latency for resilience against hiccups
+#[derive(Args, Clone, Copy, Debug)]
+struct QueueArgs {
+    /// Maximum amount of video buffered by each raw video queue, in milliseconds
+    /// [default: 10000, 200 with --low-latency]
+    #[arg(long)]
+    queue_max_time: Option<u64>,
+
+    /// Maximum number of frames buffered by each raw video queue
+    /// [default: 10000, 10 with --low-latency]
+    #[arg(long)]
+    queue_max_buffers: Option<u32>,
+
+    /// Maximum number of bytes buffered by each raw video queue [default: 1048576000]
+    #[arg(long)]
+    queue_max_bytes: Option<u32>,
+
+    /// Drop frames instead of blocking once a raw video queue is full
+    /// [default: no, downstream with --low-latency]
+    #[arg(long, value_enum)]
+    queue_leaky: Option<Leaky>,
+}
+
+impl QueueArgs {
+    /// Small queues dropping the oldest frames, for the limits not given explicitly
+    fn with_low_latency_defaults(self) -> Self {
+        QueueArgs {
+            queue_max_time: self.queue_max_time.or(Some(200)),
+            queue_max_buffers: self.queue_max_buffers.or(Some(10)),
+            queue_max_bytes: self.queue_max_bytes,
+            queue_leaky: self.queue_leaky.or(Some(Leaky::Downstream)),
+        }
+    }
+
+    fn make_queue(&self) -> anyhow::Result<gst::Element> {
+        let queue = make_element("queue")?
+            .property("max-size-bytes", self.queue_max_bytes.unwrap_or(1048576000))
+            .property("max-size-buffers", self.queue_max_buffers.unwrap_or(10000))
+            .property(
+                "max-size-time",
+                gst::ClockTime::from_mseconds(self.queue_max_time.unwrap_or(10000)).nseconds(),
+            )
+            .property_from_str("leaky", self.queue_leaky.unwrap_or(Leaky::No).as_str())
+            .build()?;
+
+        Ok(queue)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Leaky {
+    /// Block upstream
+    No,
+    /// Drop the incoming frames
+    Upstream,
+    /// Drop the oldest frames
+    Downstream,
+}
+
+impl Leaky {
+    fn as_str(self) -> &'static str {
+        match self {
+            Leaky::No => "no",
+            Leaky::Upstream => "upstream",
+            Leaky::Downstream => "downstream",
+        }
+    }
+}
+
+/// Corner of the video an overlay is drawn in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl OverlayPosition {
+    /// Horizontal and vertical alignment, as the overlay elements name them
+    fn alignment(self) -> (&'static str, &'static str) {
+        match self {
+            OverlayPosition::TopLeft => ("left", "top"),
+            OverlayPosition::TopRight => ("right", "top"),
+            OverlayPosition::BottomLeft => ("left", "bottom"),
+            OverlayPosition::BottomRight => ("right", "bottom"),
+        }
+    }
+}
+
+/// videoconvert dithering, from none to the highest quality error diffusion
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ConvertDither {
+    None,
+    Verterr,
+    FloydSteinberg,
+    SierraLite,
+    Bayer,
+}
+
+impl ConvertDither {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConvertDither::None => "none",
+            ConvertDither::Verterr => "verterr",
+            ConvertDither::FloydSteinberg => "floyd-steinberg",
+            ConvertDither::SierraLite => "sierra-lite",
+            ConvertDither::Bayer => "bayer",
+        }
+    }
+}
+
+/// videoconvert resampling, from the fastest to the sharpest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ConvertResampler {
+    Nearest,
+    Linear,
+    Cubic,
+    Sinc,
+    Lanczos,
+}
+
+impl ConvertResampler {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConvertResampler::Nearest => "nearest",
+            ConvertResampler::Linear => "linear",
+            ConvertResampler::Cubic => "cubic",
+            ConvertResampler::Sinc => "sinc",
+            ConvertResampler::Lanczos => "lanczos",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Codec {
+    H264,
+    H265,
+}
+
+impl Codec {
+    /// Each output gets its own parser, converting the encoder output to the
+    /// stream format its muxer wants (e.g. avc for FLV/MP4)
+    fn make_parser(self) -> anyhow::Result<gst::Element> {
+        Ok(make_element(self.parser_name())?.build()?)
+    }
+
+    fn parser_name(self) -> &'static str {
+        match self {
+            Codec::H264 => "h264parse",
+            Codec::H265 => "h265parse",
+        }
+    }
+}
+
+/// Whether the hardware encoder `name` is installed and initializes. In a qube
+/// the plugin may well be installed without the GPU being passed through, which
+/// only shows once the encoder opens its device going to Ready
+fn hardware_encoder_works(name: &str) -> bool {
+    let Ok(encoder) = gst::ElementFactory::make(name).build() else {
+        return false;
+    };
+
+    let works = encoder.set_state(gst::State::Ready).is_ok();
+    let _ = encoder.set_state(gst::State::Null);
+
+    if !works {
+        tracing::warn!(
+            encoder = name,
+            "hardware encoder installed but failed to initialize, falling back to software encoding"
+        );
+    }
+
+    works
+}
+
+/// Encoder element picked for the codec, depending on the available hardware
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoder {
+    NvH264,
+    OpenH264,
+    NvH265,
+    X265,
+}
+
+impl Encoder {
+    /// Prefer the NVIDIA encoder when its plugin is installed and the GPU
+    /// behind it is reachable
+    fn select(codec: Codec) -> Self {
+        match codec {
+            Codec::H264 if hardware_encoder_works("nvh264enc") => Encoder::NvH264,
+            Codec::H264 => Encoder::OpenH264,
+            Codec::H265 if hardware_encoder_works("nvh265enc") => Encoder::NvH265,
+            Codec::H265 => Encoder::X265,
+        }
+    }
+
+    fn factory_name(self) -> &'static str {
+        match self {
+            Encoder::NvH264 => "nvh264enc",
+            Encoder::OpenH264 => "openh264enc",
+            Encoder::NvH265 => "nvh265enc",
+            Encoder::X265 => "x265enc",
+        }
+    }
+
+    fn is_nvcodec(self) -> bool {
+        matches!(self, Encoder::NvH264 | Encoder::NvH265)
+    }
+
+    /// Raw video format the encoder takes best, in `colorimetry`
+    fn input_caps(self, colorimetry: &str) -> gst::Caps {
+        gst::Caps::builder("video/x-raw")
+            .field("format", if self.is_nvcodec() { "NV12" } else { "I420" })
+            .field("colorimetry", colorimetry)
+            .build()
+    }
+
+    /// Build the encoder with its default settings
+    fn make(self) -> anyhow::Result<gst::Element> {
+        let videoenc = match self {
+            Encoder::NvH264 | Encoder::NvH265 => {
+                tracing::debug!("using nvcodec");
+                make_element(self.factory_name())?
+                    .property("bitrate", 99000u32)
+                    .build()?
+            }
+            Encoder::OpenH264 => make_element(self.factory_name())?
+                .property("bitrate", 4500000u32)
+                .property("max-bitrate", 6000000u32)
+                .property_from_str("complexity", "high")
+                .property_from_str("usage-type", "screen")
+                .build()?,
+            Encoder::X265 => make_element(self.factory_name())?
+                .property("bitrate", 4500u32)
+                .property_from_str("tune", "zerolatency")
+                .build()?,
+        };
+
+        Ok(videoenc)
+    }
+
+    /// Map the rate control mode onto the encoder properties. For CBR the
+    /// VBV/buffer size is set to one second worth of bitrate.
+    fn set_rate_control(self, videoenc: &gst::Element, rate_control: RateControl) {
+        match (self, rate_control) {
+            (Encoder::NvH264 | Encoder::NvH265, RateControl::Cbr) => {
+                // both in kbit/s
+                let bitrate = videoenc.property::<u32>("bitrate");
+                videoenc.set_property_from_str("rc-mode", "cbr");
+                videoenc.set_property("vbv-buffer-size", bitrate);
+            }
+            (Encoder::NvH264 | Encoder::NvH265, RateControl::Vbr) => {
+                videoenc.set_property_from_str("rc-mode", "vbr");
+            }
+            (Encoder::OpenH264, RateControl::Cbr) => {
+                let bitrate = videoenc.property::<u32>("bitrate");
+                videoenc.set_property_from_str("rate-control", "bitrate");
+                videoenc.set_property("max-bitrate", bitrate);
+            }
+            (Encoder::OpenH264, RateControl::Vbr) => {
+                videoenc.set_property_from_str("rate-control", "quality");
+            }
+            (Encoder::X265, RateControl::Cbr) => {
+                let bitrate = videoenc.property::<u32>("bitrate");
+                videoenc.set_property(
+                    "option-string",
+                    format!("vbv-maxrate={}:vbv-bufsize={}", bitrate, bitrate),
+                );
+            }
+            // x265 defaults to average bitrate, which already varies
+            (Encoder::X265, RateControl::Vbr) => (),
+        }
+
+        tracing::debug!(encoder = ?self, ?rate_control, "set rate control");
+    }
+
+    /// Target bitrate in kbit/s, openh264 counts in bit/s
+    fn bitrate_kbps(self, videoenc: &gst::Element) -> u32 {
+        let bitrate = videoenc.property::<u32>("bitrate");
+
+        match self {
+            Encoder::OpenH264 => bitrate / 1000,
+            _ => bitrate,
+        }
+    }
+
+    fn set_bitrate_kbps(self, videoenc: &gst::Element, kbps: u32) {
+        let bitrate = match self {
+            Encoder::OpenH264 => kbps.saturating_mul(1000),
+            _ => kbps,
+        };
+
+        // openh264 caps the bitrate to its maximum
+        if self == Encoder::OpenH264 && videoenc.property::<u32>("max-bitrate") < bitrate {
+            videoenc.set_property("max-bitrate", bitrate);
+        }
+        videoenc.set_property("bitrate", bitrate);
+    }
+
+    /// Property holding the speed/quality preset, if the encoder has one
+    fn preset_property(self) -> Option<&'static str> {
+        match self {
+            Encoder::NvH264 | Encoder::NvH265 => Some("preset"),
+            Encoder::OpenH264 => None,
+            Encoder::X265 => Some("speed-preset"),
+        }
+    }
+
+    /// Property holding the tuning, if the encoder has one
+    fn tune_property(self) -> Option<&'static str> {
+        match self {
+            Encoder::X265 => Some("tune"),
+            _ => None,
+        }
+    }
+
+    /// Tune the encoder to output each frame as soon as possible: no B-frames
+    /// or lookahead, and the fastest presets
+    fn set_low_latency(self, videoenc: &gst::Element) {
+        let properties: &[(&str, &str)] = match self {
+            Encoder::NvH264 | Encoder::NvH265 => &[
+                ("preset", "low-latency-hq"),
+                ("zerolatency", "true"),
+                ("bframes", "0"),
+                ("rc-lookahead", "0"),
+            ],
+            // openh264 never emits B-frames
+            Encoder::OpenH264 => &[("complexity", "low")],
+            Encoder::X265 => &[("speed-preset", "ultrafast"), ("tune", "zerolatency")],
+        };
+
+        for (name, value) in properties {
+            if let Err(err) = try_set_property_from_str(videoenc, name, value) {
+                tracing::warn!(?err, "skipping low-latency encoder setting");
+            }
+        }
+    }
+
+    /// Apply the individually given B-frames, lookahead and zerolatency
+    /// settings, failing for those the encoder can't honor
+    fn set_latency_options(
+        self,
+        videoenc: &gst::Element,
+        bframes: Option<u32>,
+        rc_lookahead: Option<u32>,
+        zerolatency: bool,
+    ) -> anyhow::Result<()> {
+        match self {
+            Encoder::NvH264 | Encoder::NvH265 => {
+                if let Some(bframes) = bframes {
+                    try_set_property_from_str(videoenc, "bframes", &bframes.to_string())?;
+                }
+                if let Some(rc_lookahead) = rc_lookahead {
+                    try_set_property_from_str(videoenc, "rc-lookahead", &rc_lookahead.to_string())?;
+                }
+                if zerolatency {
+                    try_set_property_from_str(videoenc, "zerolatency", "true")?;
+                }
+            }
+            Encoder::OpenH264 => {
+                anyhow::ensure!(
+                    bframes.unwrap_or(0) == 0,
+                    "openh264enc never emits B-frames"
+                );
+                anyhow::ensure!(
+                    rc_lookahead.is_none(),
+                    "openh264enc has no rate control lookahead"
+                );
+                // nothing to wait for without B-frames or lookahead
+            }
+            Encoder::X265 => {
+                // appended to the options the rate control may have set
+                let options: Vec<String> = videoenc
+                    .property::<Option<String>>("option-string")
+                    .into_iter()
+                    .filter(|options| !options.is_empty())
+                    .chain(bframes.map(|bframes| format!("bframes={}", bframes)))
+                    .chain(rc_lookahead.map(|frames| format!("rc-lookahead={}", frames)))
+                    .collect();
+                videoenc.set_property("option-string", options.join(":"));
+
+                if zerolatency {
+                    videoenc.set_property_from_str("tune", "zerolatency");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum RateControl {
+    /// Constant bitrate
+    Cbr,
+    /// Variable bitrate
+    Vbr,
+}
+
+/// openh264enc complexity, trading quality for CPU time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SwComplexity {
+    Low,
+    Medium,
+    High,
+}
+
+impl SwComplexity {
+    fn as_str(self) -> &'static str {
+        match self {
+            SwComplexity::Low => "low",
+            SwComplexity::Medium => "medium",
+            SwComplexity::High => "high",
+        }
+    }
+}
+
+/// Kind of content openh264enc tunes for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SwUsageType {
+    Camera,
+    Screen,
+}
+
+impl SwUsageType {
+    fn as_str(self) -> &'static str {
+        match self {
+            SwUsageType::Camera => "camera",
+            SwUsageType::Screen => "screen",
+        }
+    }
+}
+
+/// Conversion matrix, transfer function and primaries of the encoded video
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Colorimetry {
+    /// ITU-R BT.709, the HD standard
+    Bt709,
+    /// ITU-R BT.601, the SD standard
+    Bt601,
+}
+
+impl Colorimetry {
+    /// GStreamer colorimetry string in `range`, as range:matrix:transfer:primaries
+    fn caps_string(self, range: ColorRange) -> String {
+        let range = match range {
+            ColorRange::Full => 1,
+            ColorRange::Limited => 2,
+        };
+        let (matrix, transfer, primaries) = match self {
+            Colorimetry::Bt709 => (3, 5, 1),
+            // BT.601 shares the BT.709 transfer function
+            Colorimetry::Bt601 => (4, 5, 4),
+        };
+
+        format!("{}:{}:{}:{}", range, matrix, transfer, primaries)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ColorRange {
+    /// 0-255, every shade of the captured screen
+    Full,
+    /// 16-235 for luma and 16-240 for chroma, the broadcast range
+    Limited,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum H264Profile {
+    Baseline,
+    Main,
+    High,
+}
+
+impl H264Profile {
+    fn as_str(self) -> &'static str {
+        match self {
+            H264Profile::Baseline => "baseline",
+            H264Profile::Main => "main",
+            H264Profile::High => "high",
+        }
+    }
+}
+
+/// H.264 level, bounding what a decoder has to keep up with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum H264Level {
+    #[value(name = "1")]
+    L1,
+    #[value(name = "1b")]
+    L1b,
+    #[value(name = "1.1")]
+    L1_1,
+    #[value(name = "1.2")]
+    L1_2,
+    #[value(name = "1.3")]
+    L1_3,
+    #[value(name = "2")]
+    L2,
+    #[value(name = "2.1")]
+    L2_1,
+    #[value(name = "2.2")]
+    L2_2,
+    #[value(name = "3")]
+    L3,
+    #[value(name = "3.1")]
+    L3_1,
+    #[value(name = "3.2")]
+    L3_2,
+    #[value(name = "4")]
+    L4,
+    #[value(name = "4.1")]
+    L4_1,
+    #[value(name = "4.2")]
+    L4_2,
+    #[value(name = "5")]
+    L5,
+    #[value(name = "5.1")]
+    L5_1,
+    #[value(name = "5.2")]
+    L5_2,
+    #[value(name = "6")]
+    L6,
+    #[value(name = "6.1")]
+    L6_1,
+    #[value(name = "6.2")]
+    L6_2,
+}
+
+impl H264Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            H264Level::L1 => "1",
+            H264Level::L1b => "1b",
+            H264Level::L1_1 => "1.1",
+            H264Level::L1_2 => "1.2",
+            H264Level::L1_3 => "1.3",
+            H264Level::L2 => "2",
+            H264Level::L2_1 => "2.1",
+            H264Level::L2_2 => "2.2",
+            H264Level::L3 => "3",
+            H264Level::L3_1 => "3.1",
+            H264Level::L3_2 => "3.2",
+            H264Level::L4 => "4",
+            H264Level::L4_1 => "4.1",
+            H264Level::L4_2 => "4.2",
+            H264Level::L5 => "5",
+            H264Level::L5_1 => "5.1",
+            H264Level::L5_2 => "5.2",
+            H264Level::L6 => "6",
+            H264Level::L6_1 => "6.1",
+            H264Level::L6_2 => "6.2",
+        }
+    }
+
+    /// Largest frame size in macroblocks, macroblock rate per second and
+    /// baseline/main bitrate in kbit/s, from table A-1 of the H.264 spec
+    fn limits(self) -> (u64, u64, u64) {
+        match self {
+            H264Level::L1 => (99, 1485, 64),
+            H264Level::L1b => (99, 1485, 128),
+            H264Level::L1_1 => (396, 3000, 192),
+            H264Level::L1_2 => (396, 6000, 384),
+            H264Level::L1_3 => (396, 11880, 768),
+            H264Level::L2 => (396, 11880, 2000),
+            H264Level::L2_1 => (792, 19800, 4000),
+            H264Level::L2_2 => (1620, 20250, 4000),
+            H264Level::L3 => (1620, 40500, 10000),
+            H264Level::L3_1 => (3600, 108000, 14000),
+            H264Level::L3_2 => (5120, 216000, 20000),
+            H264Level::L4 => (8192, 245760, 20000),
+            H264Level::L4_1 => (8192, 245760, 50000),
+            H264Level::L4_2 => (8704, 522240, 50000),
+            H264Level::L5 => (22080, 589824, 135000),
+            H264Level::L5_1 => (36864, 983040, 240000),
+            H264Level::L5_2 => (36864, 2073600, 240000),
+            H264Level::L6 => (139264, 4177920, 240000),
+            H264Level::L6_1 => (139264, 8355840, 480000),
+            H264Level::L6_2 => (139264, 16711680, 800000),
+        }
+    }
+}
+
+/// Ways video of `resolution` at `framerate` and `kbps` goes beyond what
+/// `level` allows, none when it fits
+fn h264_level_violations(
+    level: H264Level,
+    profile: Option<H264Profile>,
+    resolution: Resolution,
+    framerate: gst::Fraction,
+    kbps: u32,
+) -> Vec<String> {
+    let (max_frame_size, max_rate, max_kbps) = level.limits();
+    // high profile streams get a quarter more bitrate at the same level
+    let max_kbps = match profile {
+        Some(H264Profile::High) => max_kbps * 5 / 4,
+        _ => max_kbps,
+    };
+
+    let width = u64::from(resolution.width.unsigned_abs()).div_ceil(16);
+    let height = u64::from(resolution.height.unsigned_abs()).div_ceil(16);
+    let frame_size = width * height;
+    let rate = frame_size * u64::from(framerate.numer().unsigned_abs())
+        / u64::from(framerate.denom().unsigned_abs()).max(1);
+
+    let mut violations = Vec::new();
+    if frame_size > max_frame_size {
+        violations.push(format!(
+            "{} is {} macroblocks, above the {} of level {}",
+            resolution,
+            frame_size,
+            max_frame_size,
+            level.as_str()
+        ));
+    }
+    // neither side may be longer than that of a square frame eight times as large
+    let max_side = (8 * max_frame_size).isqrt();
+    if width.max(height) > max_side {
+        violations.push(format!(
+            "{} is wider or taller than the {} pixels of level {}",
+            resolution,
+            max_side * 16,
+            level.as_str()
+        ));
+    }
+    if rate > max_rate {
+        violations.push(format!(
+            "{} at {}fps is {} macroblocks per second, above the {} of level {}",
+            resolution,
+            framerate,
+            rate,
+            max_rate,
+            level.as_str()
+        ));
+    }
+    if u64::from(kbps) > max_kbps {
+        violations.push(format!(
+            "{}kbit/s is above the {}kbit/s of level {}",
+            kbps,
+            max_kbps,
+            level.as_str()
+        ));
+    }
+
+    violations
+}
+
+/// Container of the local recording
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum RecordFormat {
+    /// FLV, same container sent over RTMP
+    Flv,
+    /// Fragmented MP4, seekable and readable up to the last fragment after a crash
+    Mp4,
+    /// Matroska
+    Mkv,
+    /// WebM with VP9 video and Opus audio, encoded separately from the stream.
+    /// VP9 in software is CPU heavy
+    Webm,
+}
+
+impl RecordFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            RecordFormat::Flv => "flv",
+            RecordFormat::Mp4 => "mp4",
+            RecordFormat::Mkv => "mkv",
+            RecordFormat::Webm => "webm",
+        }
+    }
+
+    /// Whether the recording is encoded from the raw video and audio, rather
+    /// than muxing the streamed H.264/H.265 and AAC
+    fn reencodes(self) -> bool {
+        self == RecordFormat::Webm
+    }
+
+    fn make_muxer(self) -> anyhow::Result<gst::Element> {
+        let muxer = make_element(self.muxer_name())?.build()?;
+
+        // moov is written upfront and a fragment every second, so the file
+        // starts playing right away and a crash loses at most the last fragment
+        if self == RecordFormat::Mp4 {
+            muxer.set_property("fragment-duration", 1000u32);
+        }
+
+        Ok(muxer)
+    }
+
+    fn muxer_name(self) -> &'static str {
+        match self {
+            RecordFormat::Flv => "flvmux",
+            RecordFormat::Mp4 => "mp4mux",
+            RecordFormat::Mkv => "matroskamux",
+            RecordFormat::Webm => "webmmux",
+        }
+    }
+}
+
+/// Sample rates commonly accepted by AAC decoders and RTMP ingests
+const AUDIO_RATES: [i32; 5] = [22050, 24000, 32000, 44100, 48000];
+
+/// Range of the `equalizer-10bands` band gains, in dB
+const EQ_MIN_GAIN: f64 = -24.0;
+const EQ_MAX_GAIN: f64 = 12.0;
+
+#[derive(Debug)]
+pub struct VideoInfo {
+    pub width: i32,
+    pub height: i32,
+    pub format: String,
+    pub framerate: gst::Fraction,
+    pub colorimetry: String,
+    /// Bytes per frame, row padding included, 0 for the default layout of the format
+    pub frame_size: u32,
+    /// Where each plane starts within a frame and its row stride, empty for
+    /// the default layout of the format
+    pub planes: Vec<PlaneLayout>,
+}
+
+/// Offset and row stride of a plane within a raw frame, in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlaneLayout {
+    pub offset: u32,
+    pub stride: u32,
+}
+
+/// Pair up the plane `offsets` and `strides` reported by GStreamer
+fn plane_layouts(offsets: &[usize], strides: &[i32]) -> anyhow::Result<Vec<PlaneLayout>> {
+    offsets
+        .iter()
+        .zip(strides)
+        .map(|(&offset, &stride)| {
+            Ok(PlaneLayout {
+                offset: u32::try_from(offset).context("plane offset out of range")?,
+                // negative strides flip the image, which rawvideoparse can't express
+                stride: u32::try_from(stride).context("negative plane stride")?,
+            })
+        })
+        .collect()
+}
+
+impl VideoInfo {
+    /// Stand-in for the producer header when only building the pipeline,
+    /// caps aren't negotiated before Paused so any valid values do
+    fn dry_run() -> Self {
+        VideoInfo {
+            width: 1920,
+            height: 1080,
+            format: "BGRx".into(),
+            framerate: gst::Fraction::new(25, 1),
+            colorimetry: "sRGB".into(),
+            frame_size: 0,
+            planes: Vec::new(),
+        }
+    }
+}
+
+fn make_videocrop() -> anyhow::Result<gst::Element> {
+    let videocrop = make_element("videocrop")?
+        .property("left", 2i32)
+        .property("right", 1922i32)
+        .property("top", 18i32)
+        .property("bottom", 21i32)
+        .build()?;
+
+    Ok(videocrop)
+}
+
+/// Capture elements for `args`, cropped to `region` when given
+pub fn make_capture_chain(
+    args: &ProduceArgs,
+    region: Option<Region>,
+) -> anyhow::Result<Vec<gst::Element>> {
+    if args.test_source {
+        let source = make_element("videotestsrc")?
+            .property("is-live", true)
+            .property_from_str("pattern", "smpte")
+            .build()?;
+
+        let caps = make_element("capsfilter")?
+            .property(
+                "caps",
+                gst::Caps::builder("video/x-raw")
+                    .field("format", "BGRx")
+                    .field("width", 640i32)
+                    .field("height", 480i32)
+                    .field("framerate", gst::Fraction::new(25, 1))
+                    .build(),
+            )
+            .build()?;
+
+        return Ok(vec![source, caps]);
+    }
+
+    if let Some(device) = args.v4l2_device() {
+        let source = make_element("v4l2src")?
+            .property("device", device.to_string_lossy().as_ref())
+            .build()?;
+
+        // cameras often prefer MJPEG, the receiver only takes raw video, in
+        // whatever raw format and size the camera offers first
+        let caps = make_element("capsfilter")?
+            .property("caps", gst::Caps::new_empty_simple("video/x-raw"))
+            .build()?;
+
+        return Ok(vec![source, caps]);
+    }
+
+    // shared by the probe and the capture, as damage mode can change the caps
+    let source = make_element("ximagesrc")?
+        .property("use-damage", args.use_damage)
+        .build()?;
+
+    if let Some(show_pointer) = args.show_pointer() {
+        source.set_property("show-pointer", show_pointer);
+    }
+    if let Some(display) = &args.display {
+        source.set_property("display-name", display.as_str());
+    }
+
+    // the crop trims the full display, a window is captured as is
+    if let Some(xid) = args.xid {
+        source.set_property("xid", xid);
+        return Ok(vec![source]);
+    }
+    if let Some(window_name) = &args.window_name {
+        source.set_property("xname", window_name.as_str());
+        return Ok(vec![source]);
+    }
+    // the end coordinates are inclusive
+    if let Some(region) = region {
+        source.set_property("startx", region.x);
+        source.set_property("starty", region.y);
+        source.set_property("endx", region.x + region.width - 1);
+        source.set_property("endy", region.y + region.height - 1);
+        return Ok(vec![source]);
+    }
+
+    Ok(vec![source, make_videocrop()?])
+}
+
+/// Space between the --pip inset and the edges of the display, in pixels
+const PIP_MARGIN: i32 = 16;
+
+/// Bin compositing the camera at `device` as an inset over the display
+/// captured by `screen`, both brought to the same framerate first as the
+/// compositor outputs at a single one
+fn make_pip_bin(
+    args: &ProduceArgs,
+    screen: Vec<gst::Element>,
+    device: &Path,
+) -> anyhow::Result<gst::Element> {
+    let bin = gst::Bin::builder().name("pip").build();
+    let framerate = gst::Fraction::new(args.fallback_framerate, 1);
+
+    let make_framerate_chain = || -> anyhow::Result<Vec<gst::Element>> {
+        Ok(vec![
+            make_element("videoconvert")?.build()?,
+            make_element("videorate")?.build()?,
+            make_element("capsfilter")?
+                .property(
+                    "caps",
+                    gst::Caps::builder("video/x-raw")
+                        .field("framerate", framerate)
+                        .build(),
+                )
+                .build()?,
+        ])
+    };
+
+    let mut screen_chain = screen;
+    screen_chain.extend(make_framerate_chain()?);
+
+    let mut camera_chain = vec![
+        make_element("v4l2src")?
+            .property("device", device.to_string_lossy().as_ref())
+            .build()?,
+        make_element("capsfilter")?
+            .property("caps", gst::Caps::new_empty_simple("video/x-raw"))
+            .build()?,
+    ];
+    camera_chain.extend(make_framerate_chain()?);
+
+    let compositor = make_element("compositor")?
+        .property_from_str("background", "black")
+        .build()?;
+    // the receiver gets the same format as from a bare display capture
+    let compositorconfig = make_element("capsfilter")?
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("format", "BGRx")
+                .build(),
+        )
+        .build()?;
+
+    bin.add_many(&screen_chain)
+        .context("adding the screen chain")?;
+    bin.add_many(&camera_chain)
+        .context("adding the camera chain")?;
+    bin.add_many(&[&compositor, &compositorconfig])
+        .context("adding the compositor")?;
+
+    link_chain("screen chain", &screen_chain)?;
+    link_chain("camera chain", &camera_chain)?;
+    compositor
+        .link(&compositorconfig)
+        .context("linking compositor")?;
+
+    let link_to_compositor = |chain: &[gst::Element]| -> anyhow::Result<gst::Pad> {
+        let sinkpad = compositor
+            .request_pad_simple("sink_%u")
+            .context("requesting sink pad from compositor")?;
+        chain
+            .last()
+            .and_then(|element| element.static_pad("src"))
+            .context("capture chain without src pad")?
+            .link(&sinkpad)
+            .context("linking to compositor")?;
+
+        Ok(sinkpad)
+    };
+
+    let screen_pad = link_to_compositor(&screen_chain)?;
+    let camera_pad = link_to_compositor(&camera_chain)?;
+
+    let size = args.pip_size;
+    camera_pad.set_property("zorder", 1u32);
+    camera_pad.set_property("width", size.width);
+    camera_pad.set_property("height", size.height);
+    camera_pad.set_property_from_str("sizing-policy", "keep-aspect-ratio");
+
+    // the corner is only known once the display size is
+    let position = args.pip_position;
+    screen_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        let Some(gst::PadProbeData::Event(event)) = &info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let gst::EventView::Caps(caps) = event.view() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let Some(structure) = caps.caps().structure(0) else {
+            return gst::PadProbeReturn::Ok;
+        };
+
+        if let (Ok(width), Ok(height)) = (
+            structure.get::<i32>("width"),
+            structure.get::<i32>("height"),
+        ) {
+            let (xpos, ypos) = match position {
+                OverlayPosition::TopLeft => (PIP_MARGIN, PIP_MARGIN),
+                OverlayPosition::TopRight => (width - size.width - PIP_MARGIN, PIP_MARGIN),
+                OverlayPosition::BottomLeft => (PIP_MARGIN, height - size.height - PIP_MARGIN),
+                OverlayPosition::BottomRight => (
+                    width - size.width - PIP_MARGIN,
+                    height - size.height - PIP_MARGIN,
+                ),
+            };
+
+            tracing::debug!(width, height, xpos, ypos, "placing the camera inset");
+            camera_pad.set_property("xpos", xpos.max(0));
+            camera_pad.set_property("ypos", ypos.max(0));
+        }
+
+        gst::PadProbeReturn::Ok
+    });
+
+    let srcpad = compositorconfig
+        .static_pad("src")
+        .context("capsfilter without src pad")?;
+    let pad = gst::GhostPad::builder_with_target(&srcpad)?
+        .name("src")
+        .build();
+    bin.add_pad(&pad)?;
+
+    Ok(bin.upcast())
+}
+
+/// Make sure the window to capture exists, as ximagesrc silently falls back
+/// to capturing the whole display otherwise
+fn check_window(args: &ProduceArgs) -> anyhow::Result<()> {
+    let (flag, window) = match (args.xid, &args.window_name) {
+        (Some(xid), _) => ("-id", xid.to_string()),
+        (None, Some(window_name)) => ("-name", window_name.clone()),
+        (None, None) => return Ok(()),
+    };
+
+    let mut command = std::process::Command::new("xwininfo");
+    if let Some(display) = &args.display {
+        command.args(["-display", display]);
+    }
+
+    let output = command
+        .args([flag, &window])
+        .output()
+        .context("running xwininfo to look up the window")?;
+
+    anyhow::ensure!(
+        output.status.success(),
+        "window {} not found: {}",
+        window,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    Ok(())
+}
+
+/// Video info to announce to the receiver, from the caps of the first
+/// captured buffer, with the default frame layout of those caps
+pub fn videoinfo_from_caps(
+    caps: &gst::CapsRef,
+    fallback_framerate: gst::Fraction,
+) -> anyhow::Result<VideoInfo> {
+    let structure = caps.structure(0).context("empty caps")?;
+
+    let width = structure.get::<i32>("width")?;
+    let height = structure.get::<i32>("height")?;
+    let format = structure.get::<String>("format")?;
+    // cameras report theirs, RGB captures leave it to the sRGB default
+    let colorimetry = structure
+        .get::<String>("colorimetry")
+        .unwrap_or_else(|_| "sRGB".into());
+
+    // ximagesrc may report a variable (0/1) framerate, which can't be
+    // used to build the fixed raw video caps on the receiver
+    let framerate = match structure.get::<gst::Fraction>("framerate") {
+        Ok(framerate) if framerate.numer() > 0 => framerate,
+        Ok(framerate) => {
+            tracing::warn!(
+                %framerate,
+                %fallback_framerate,
+                "variable framerate reported, using fallback"
+            );
+            fallback_framerate
+        }
+        Err(err) => {
+            tracing::warn!(
+                ?err,
+                %fallback_framerate,
+                "no fixed framerate reported, using fallback"
+            );
+            fallback_framerate
+        }
+    };
+
+    let layout = gst_video::VideoInfo::from_caps(caps).context("parsing raw video caps")?;
+
+    Ok(VideoInfo {
+        width,
+        height,
+        format,
+        framerate,
+        colorimetry,
+        frame_size: u32::try_from(layout.size()).context("frame size out of range")?,
+        planes: plane_layouts(layout.offset(), layout.stride())?,
+    })
+}
+
+/// Video info of `buffer` with `caps`, the frame layout taken from its video
+/// meta when it has one
+fn videoinfo_from_buffer(
+    caps: &gst::Caps,
+    buffer: Option<&gst::BufferRef>,
+    fallback_framerate: gst::Fraction,
+) -> anyhow::Result<VideoInfo> {
+    let mut video_info = videoinfo_from_caps(caps, fallback_framerate)?;
+
+    // ximagesrc may pad the rows, which only its video meta tells
+    let meta =
+        buffer.and_then(|buffer| Some((buffer.size(), buffer.meta::<gst_video::VideoMeta>()?)));
+    if let Some((size, meta)) = meta {
+        video_info.planes = plane_layouts(meta.offset(), meta.stride())?;
+        video_info.frame_size = u32::try_from(size).context("frame size out of range")?;
+    }
+
+    Ok(video_info)
+}
+
+/// Send a new video info header to `output` whenever the caps of the video
+/// written out through `pad`, a sink pad, change mid-stream, e.g. when the
+/// captured window is resized. It's written from the sink's streaming thread,
+/// so right in between the last frame with the old caps and the first with
+/// the new ones, where the receiver looks for it.
+fn announce_caps_changes(
+    pad: &gst::Pad,
+    output: File,
+    fallback_framerate: gst::Fraction,
+    main_loop: &glib::MainLoop,
+    pipeline_error: &PipelineError,
+) -> anyhow::Result<()> {
+    // the first caps are announced ahead of the first buffer
+    let announced_caps = Mutex::new(None::<gst::Caps>);
+    let main_loop = main_loop.clone();
+    let pipeline_error = pipeline_error.clone();
+
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let Some(caps) = pad.current_caps() else {
+            return gst::PadProbeReturn::Ok;
+        };
+
+        let mut announced_caps = announced_caps.lock().unwrap();
+        if announced_caps.as_ref() == Some(&caps) {
+            return gst::PadProbeReturn::Ok;
+        }
+        if announced_caps.replace(caps.clone()).is_none() {
+            return gst::PadProbeReturn::Ok;
+        }
+
+        let result = videoinfo_from_buffer(&caps, info.buffer(), fallback_framerate).and_then(
+            |video_info| {
+                tracing::info!(?video_info, "captured video info changed");
+                send_stream_videoinfo(&mut &output, &video_info)
+            },
+        );
+
+        match result {
+            Ok(()) => gst::PadProbeReturn::Ok,
+            Err(err) => {
+                pipeline_error
+                    .lock()
+                    .unwrap()
+                    .get_or_insert(err.context("announcing new video info"));
+                main_loop.quit();
+
+                gst::PadProbeReturn::Drop
+            }
+        }
+    })
+    .context("watching the video caps")?;
+
+    Ok(())
+}
+
+/// Send the video info header to the receiver from the caps of the first
+/// buffer leaving `pad`, before that buffer is written out. Reading the caps
+/// off the real capture avoids starting a separate one just to probe them,
+/// which grabbed the display twice and could flicker.
+///
+/// The header is written to `output`, the data descriptor, and also opens
+/// `dump` when dumping the raw video to a file.
+///
+/// Stops `main_loop` when the header can't be sent, or when no frame came
+/// within `--probe-timeout`.
+fn announce_videoinfo(
+    args: &ProduceArgs,
+    region: Option<Region>,
+    pad: &gst::Pad,
+    output: File,
+    dump: Option<File>,
+    main_loop: &glib::MainLoop,
+    pipeline_error: &PipelineError,
+) -> anyhow::Result<()> {
+    let fallback_framerate = gst::Fraction::new(args.fallback_framerate, 1);
+    let announced = Arc::new(AtomicBool::new(false));
+
+    pad.add_probe(gst::PadProbeType::BUFFER, {
+        let announced = announced.clone();
+        let main_loop = main_loop.clone();
+        let pipeline_error = pipeline_error.clone();
+
+        move |pad, info| {
+            announced.store(true, Ordering::Relaxed);
+            let buffer = info.buffer();
+
+            let result = pad
+                .current_caps()
+                .context("first buffer without caps")
+                .and_then(|caps| videoinfo_from_buffer(&caps, buffer, fallback_framerate))
+                .and_then(|video_info| {
+                    tracing::info!(?video_info, "captured video info");
+
+                    // ximagesrc clamps the region to the display instead of failing
+                    if let Some(region) = region {
+                        anyhow::ensure!(
+                            u32::try_from(video_info.width) == Ok(region.width)
+                                && u32::try_from(video_info.height) == Ok(region.height),
+                            "region {:?} doesn't fit the display, captured {}x{}",
+                            region,
+                            video_info.width,
+                            video_info.height
+                        );
+                    }
+
+                    if let Some(mut dump) = dump.as_ref() {
+                        send_stream_videoinfo(&mut dump, &video_info)
+                            .context("writing the dump file")?;
+                    }
+
+                    send_stream_videoinfo(&mut &output, &video_info)
+                });
+
+            match result {
+                Ok(()) => gst::PadProbeReturn::Remove,
+                Err(err) => {
+                    pipeline_error
+                        .lock()
+                        .unwrap()
+                        .get_or_insert(err.context("announcing video info"));
+                    main_loop.quit();
+
+                    gst::PadProbeReturn::Drop
+                }
+            }
+        }
+    })
+    .context("watching the first captured buffer")?;
+
+    let probe_timeout = Duration::from_secs(args.probe_timeout);
+    let main_loop = main_loop.clone();
+    let pipeline_error = pipeline_error.clone();
+
+    glib::timeout_add_once(probe_timeout, move || {
+        if !announced.load(Ordering::Relaxed) {
+            pipeline_error.lock().unwrap().get_or_insert_with(|| {
+                anyhow::anyhow!("no frame captured after {:?}", probe_timeout)
+            });
+            main_loop.quit();
+        }
+    });
+
+    Ok(())
+}
+
+/// Play `pipeline`, reporting the error the failing element posted on the
+/// bus, e.g. ximagesrc without an X display, rather than a bare state change
+/// failure
+fn play(pipeline: &gst::Pipeline) -> anyhow::Result<()> {
+    if let Err(err) = pipeline.set_state(gst::State::Playing) {
+        let bus_err = pipeline
+            .bus()
+            .and_then(|bus| bus.pop_filtered(&[gst::MessageType::Error]))
+            .map(|msg| match msg.view() {
+                MessageView::Error(err) => bus_error(&msg, &err),
+                _ => unreachable!("filtered for errors"),
+            });
+
+        return Err(bus_err.unwrap_or_else(|| anyhow::Error::new(err).context("playing pipeline")));
+    }
+    tracing::debug!("playing");
+
+    Ok(())
+}
+
+/// Magic bytes opening the video info header, used to detect a desynced stream
+const VIDEOINFO_MAGIC: [u8; 4] = *b"QSVI";
+
+/// Version of the video info header, bumped on every layout change
+pub const PROTOCOL_VERSION: u8 = 4;
+
+/// Size of the fixed part of the header: magic, version, width, height,
+/// framerate numerator and denominator
+const VIDEOINFO_HEADER_LEN: usize = 4 + 1 + 4 + 4 + 4 + 4;
+
+/// Upper bound for the string fields (pixel format, colorimetry), real values
+/// like "BGRx" or "sRGB" are a handful of bytes. Checked before allocating so a
+/// corrupt or malicious producer can't make the receiver allocate arbitrary
+/// amounts of memory.
+const MAX_FORMAT_LEN: usize = 32;
+
+/// Most planes a raw video format has, as GStreamer defines it
+const MAX_PLANES: usize = 4;
+
+/// Write a length-prefixed string field
+fn write_string_field(dest: &mut impl Write, name: &str, value: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        value.len() <= MAX_FORMAT_LEN,
+        "video {} too long: {} bytes",
+        name,
+        value.len()
+    );
+
+    dest.write_all(&(value.len() as u32).to_be_bytes())?;
+    dest.write_all(value.as_bytes())?;
+
+    Ok(())
+}
+
+/// Failures decoding the video info header sent by the producer
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    /// The stream ended, or failed, before the whole header was read
+    #[error("truncated video info header")]
+    TruncatedHeader(#[source] std::io::Error),
+    /// Not a header at all, the stream is out of sync
+    #[error("bad video info magic {0:02x?}, stream is out of sync")]
+    BadMagic([u8; 4]),
+    #[error("unsupported protocol version {0}, expected {PROTOCOL_VERSION}")]
+    UnsupportedVersion(u8),
+    #[error("implausible video {field} length {len}")]
+    FormatTooLong { field: &'static str, len: usize },
+    #[error("video {field} is not valid UTF-8")]
+    InvalidUtf8 {
+        field: &'static str,
+        #[source]
+        source: std::string::FromUtf8Error,
+    },
+    /// A well formed header carrying values the receiver can't use
+    #[error("invalid video info: {0}")]
+    InvalidValue(&'static str),
+}
+
+/// Read a length-prefixed string field written by `write_string_field`
+fn read_string_field(src: &mut impl Read, field: &'static str) -> Result<String, ProtocolError> {
+    let mut len_buf = [0u8; 4];
+    src.read_exact(&mut len_buf)
+        .map_err(ProtocolError::TruncatedHeader)?;
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FORMAT_LEN {
+        return Err(ProtocolError::FormatTooLong { field, len });
+    }
+
+    let mut buf = vec![0; len];
+    src.read_exact(&mut buf)
+        .map_err(ProtocolError::TruncatedHeader)?;
+
+    String::from_utf8(buf).map_err(|source| ProtocolError::InvalidUtf8 { field, source })
+}
+
+/// Read a big-endian u32 field
+fn read_u32_field(src: &mut impl Read) -> Result<u32, ProtocolError> {
+    let mut buf = [0u8; 4];
+    src.read_exact(&mut buf)
+        .map_err(ProtocolError::TruncatedHeader)?;
+
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Pack the video info into bytes and send over `dest` (usually stdout).
+/// It can be received calling `recv_stream_videoinfo` if stdout and stdin are connected.
+/// The producer sends it again in between two frames whenever its capture
+/// changes, the receiver then parses the frames after it accordingly.
+///
+/// The header layout is: 4 bytes magic, 1 byte version, then big-endian u32
+/// width, height, framerate numerator and denominator, followed by the format
+/// and colorimetry names, each prefixed by its u32 length, then the u32 frame
+/// size and a 1 byte plane count followed by the u32 offset and stride of
+/// each plane.
+pub fn send_stream_videoinfo(dest: &mut impl Write, video_info: &VideoInfo) -> anyhow::Result<()> {
+    let width = u32::try_from(video_info.width).context("negative video width")?;
+    let height = u32::try_from(video_info.height).context("negative video height")?;
+    let framerate_numer =
+        u32::try_from(video_info.framerate.numer()).context("negative framerate")?;
+    let framerate_denom =
+        u32::try_from(video_info.framerate.denom()).context("negative framerate")?;
+
+    dest.write_all(&VIDEOINFO_MAGIC)?;
+    dest.write_all(&[PROTOCOL_VERSION])?;
+    dest.write_all(&width.to_be_bytes())?;
+    dest.write_all(&height.to_be_bytes())?;
+    dest.write_all(&framerate_numer.to_be_bytes())?;
+    dest.write_all(&framerate_denom.to_be_bytes())?;
+    write_string_field(dest, "format", &video_info.format)?;
+    write_string_field(dest, "colorimetry", &video_info.colorimetry)?;
+
+    anyhow::ensure!(
+        video_info.planes.len() <= MAX_PLANES,
+        "{} planes, raw video has at most {}",
+        video_info.planes.len(),
+        MAX_PLANES
+    );
+    dest.write_all(&video_info.frame_size.to_be_bytes())?;
+    dest.write_all(&[video_info.planes.len() as u8])?;
+    for plane in &video_info.planes {
+        dest.write_all(&plane.offset.to_be_bytes())?;
+        dest.write_all(&plane.stride.to_be_bytes())?;
+    }
+    dest.flush()?;
+
+    Ok(())
+}
+
+/// Unpack the video info from `src` (usually stdin) and rebuild the video info
+pub fn recv_stream_videoinfo(src: &mut impl Read) -> Result<VideoInfo, ProtocolError> {
+    let mut buffer = [0u8; VIDEOINFO_HEADER_LEN];
+    src.read_exact(&mut buffer)
+        .map_err(ProtocolError::TruncatedHeader)?;
+
+    let field = |start: usize| u32::from_be_bytes(buffer[start..start + 4].try_into().unwrap());
+
+    let magic: [u8; 4] = buffer[0..4].try_into().unwrap();
+    if magic != VIDEOINFO_MAGIC {
+        return Err(ProtocolError::BadMagic(magic));
+    }
+    if buffer[4] != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(buffer[4]));
+    }
+
+    let width = field(5);
+    let height = field(9);
+    let framerate_numer = field(13);
+    let framerate_denom = field(17);
+
+    if framerate_denom == 0 {
+        return Err(ProtocolError::InvalidValue("zero framerate denominator"));
+    }
+
+    let format = read_string_field(src, "format")?;
+    let colorimetry = read_string_field(src, "colorimetry")?;
+
+    let frame_size = read_u32_field(src)?;
+    if frame_size == 0 {
+        return Err(ProtocolError::InvalidValue("zero frame size"));
+    }
+
+    let mut plane_count = [0u8; 1];
+    src.read_exact(&mut plane_count)
+        .map_err(ProtocolError::TruncatedHeader)?;
+    if usize::from(plane_count[0]) > MAX_PLANES {
+        return Err(ProtocolError::InvalidValue("too many planes"));
+    }
+
+    let planes = (0..plane_count[0])
+        .map(|_| {
+            Ok(PlaneLayout {
+                offset: read_u32_field(src)?,
+                stride: read_u32_field(src)?,
+            })
+        })
+        .collect::<Result<Vec<_>, ProtocolError>>()?;
+
+    Ok(VideoInfo {
+        width: i32::try_from(width)
+            .map_err(|_| ProtocolError::InvalidValue("video width out of range"))?,
+        height: i32::try_from(height)
+            .map_err(|_| ProtocolError::InvalidValue("video height out of range"))?,
+        format,
+        framerate: gst::Fraction::new(
+            i32::try_from(framerate_numer)
+                .map_err(|_| ProtocolError::InvalidValue("framerate out of range"))?,
+            i32::try_from(framerate_denom)
+                .map_err(|_| ProtocolError::InvalidValue("framerate out of range"))?,
+        ),
+        colorimetry,
+        frame_size,
+        planes,
+    })
+}
+
+/// Direction the raw video flows through a data file descriptor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FdDirection {
+    Read,
+    Write,
+}
+
+/// Check `fd` is open in `direction` and duplicate it for writing or reading
+/// the video info header, so a wrong `--fd` fails right away instead of
+/// silently streaming nothing
+fn open_data_fd(fd: RawFd, direction: FdDirection) -> anyhow::Result<File> {
+    // SAFETY: fcntl reports a closed descriptor as EBADF, and the borrow
+    // doesn't outlive this function
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+
+    let flags = fcntl(borrowed, FcntlArg::F_GETFL)
+        .with_context(|| format!("file descriptor {} is not open", fd))?;
+    let mode = OFlag::from_bits_truncate(flags) & OFlag::O_ACCMODE;
+    let (usable, purpose) = match direction {
+        FdDirection::Read => (mode != OFlag::O_WRONLY, "reading"),
+        FdDirection::Write => (mode != OFlag::O_RDONLY, "writing"),
+    };
+    anyhow::ensure!(usable, "file descriptor {} is not open for {}", fd, purpose);
+
+    let owned = borrowed
+        .try_clone_to_owned()
+        .with_context(|| format!("duplicating file descriptor {}", fd))?;
+
+    Ok(File::from(owned))
+}
+
+/// Value of an option in a --config file
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ConfigValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    List(Vec<ConfigValue>),
+}
+
+impl ConfigValue {
+    fn to_arg_value(&self) -> Option<String> {
+        match self {
+            ConfigValue::Bool(value) => Some(value.to_string()),
+            ConfigValue::Integer(value) => Some(value.to_string()),
+            ConfigValue::Float(value) => Some(value.to_string()),
+            ConfigValue::String(value) => Some(value.clone()),
+            ConfigValue::List(_) => None,
+        }
+    }
+}
+
+/// Options left out of --print-config, so its output can be shared
+const SECRET_OPTIONS: &[&str] = &["twitch-key", "rtmp-stream-key"];
+
+/// Path given to --config on the command line, if any
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(|path| PathBuf::from(path.as_ref()));
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.into());
+        }
+    }
+
+    None
+}
+
+/// Ids of the arguments set by `args`, the command line following the
+/// `subcommand` name: options by name and positionals by their place
+fn command_line_ids(
+    command: &clap::Command,
+    subcommand: &clap::Command,
+    args: &[OsString],
+) -> Vec<String> {
+    let by_long = |name: &str| {
+        subcommand
+            .get_arguments()
+            .chain(command.get_arguments())
+            .find(|arg| arg.get_long() == Some(name))
+    };
+    let by_short = |short: Option<char>| {
+        subcommand
+            .get_arguments()
+            .chain(command.get_arguments())
+            .find(|arg| short.is_some() && arg.get_short() == short)
+    };
+
+    let mut positionals = subcommand.get_positionals().collect::<Vec<_>>();
+    positionals.sort_by_key(|arg| arg.get_index());
+    let mut positionals = positionals.into_iter();
+
+    let mut ids = Vec::new();
+    let mut only_positionals = false;
+    let mut tokens = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(token) = tokens.next() {
+        // whether the value, if the argument takes one, is part of the token
+        let (arg, inline_value) = if only_positionals || token == "-" || !token.starts_with('-') {
+            (positionals.next(), true)
+        } else if token == "--" {
+            only_positionals = true;
+            continue;
+        } else if let Some(long) = token.strip_prefix("--") {
+            let name = long.split('=').next().unwrap_or(long);
+            (by_long(name), long.contains('='))
+        } else {
+            (by_short(token.chars().nth(1)), token.chars().count() > 2)
+        };
+
+        let Some(arg) = arg else {
+            continue;
+        };
+        if !inline_value && arg.get_action().takes_values() {
+            tokens.next();
+        }
+        ids.push(arg.get_id().to_string());
+    }
+
+    ids
+}
+
+/// Options of a config file, JSON when named *.json and TOML otherwise
+fn load_config(path: &Path) -> anyhow::Result<BTreeMap<String, ConfigValue>> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+    let options = if path
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        serde_json::from_str(&contents).with_context(|| format!("parsing {}", path.display()))?
+    } else {
+        toml::from_str(&contents).with_context(|| format!("parsing {}", path.display()))?
+    };
+
+    Ok(options)
+}
+
+/// Insert the options of the file given to --config, if any, right after the
+/// subcommand in `args`, so that the options given on the command line after
+/// them take precedence.
+///
+/// The file maps the long option names of the subcommand, or the names of its
+/// positional arguments, to their values: flags take a boolean, repeatable
+/// options a list.
+pub fn expand_config_args(
+    command: &clap::Command,
+    args: Vec<OsString>,
+) -> anyhow::Result<Vec<OsString>> {
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+
+    // of the global options before the subcommand, only --config takes a value
+    let mut index = 1;
+    let position = loop {
+        let Some(arg) = args.get(index) else {
+            return Ok(args);
+        };
+        if arg == "--config" {
+            index += 2;
+        } else if arg.to_string_lossy().starts_with('-') {
+            index += 1;
+        } else {
+            break index;
+        }
+    };
+    let subcommand = command
+        .find_subcommand(&args[position])
+        .with_context(|| format!("unknown subcommand {:?}", args[position]))?;
+
+    let given = command_line_ids(command, subcommand, &args[position + 1..]);
+
+    let mut options = Vec::new();
+    let mut positionals = BTreeMap::new();
+
+    for (key, value) in load_config(&path)? {
+        let name = key.replace('_', "-");
+        let arg = subcommand
+            .get_arguments()
+            .find(|arg| {
+                arg.get_long() == Some(name.as_str())
+                    || arg.get_id().as_str().replace('_', "-") == name
+            })
+            .with_context(|| format!("unknown option {} in {}", key, path.display()))?;
+
+        // options set once are overridden by a later occurrence, but positionals
+        // and repeatable options would add to the command line ones instead
+        let adds_up =
+            arg.get_index().is_some() || matches!(arg.get_action(), clap::ArgAction::Append);
+        if adds_up && given.iter().any(|id| id == arg.get_id().as_str()) {
+            continue;
+        }
+
+        let values = match &value {
+            ConfigValue::List(values) => values.iter().collect(),
+            value => vec![value],
+        };
+
+        for value in values {
+            let value = value
+                .to_arg_value()
+                .with_context(|| format!("invalid value for {} in {}", key, path.display()))?;
+
+            match (arg.get_index(), arg.get_action().takes_values()) {
+                (Some(index), _) => {
+                    positionals.insert(index, value);
+                }
+                (None, true) => options.push(format!("--{}={}", name, value)),
+                (None, false) if value == "true" => options.push(format!("--{}", name)),
+                (None, false) => (),
+            }
+        }
+    }
+
+    // the positionals left to the file come after those on the command line
+    let mut expanded = args[..=position].to_vec();
+    expanded.extend(options.into_iter().map(OsString::from));
+    expanded.extend(args[position + 1..].iter().cloned());
+    if !positionals.is_empty() && !args[position + 1..].iter().any(|arg| arg == "--") {
+        expanded.push("--".into());
+    }
+    expanded.extend(positionals.into_values().map(OsString::from));
+
+    Ok(expanded)
+}
+
+/// Every option of `command` as set in `matches`, defaults included, as a
+/// TOML config file that --config takes back
+pub fn effective_config(
+    command: &clap::Command,
+    matches: &clap::ArgMatches,
+) -> anyhow::Result<String> {
+    let mut config = toml::Table::new();
+    let mut omitted = Vec::new();
+
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Ok(Some(values)) = matches.try_get_raw(id) else {
+            continue;
+        };
+
+        let name = arg
+            .get_long()
+            .map(str::to_owned)
+            .unwrap_or_else(|| id.replace('_', "-"));
+        if SECRET_OPTIONS.contains(&name.as_str()) {
+            omitted.push(name);
+            continue;
+        }
+
+        let values: Vec<String> = values
+            .map(|value| value.to_string_lossy().into_owned())
+            .collect();
+
+        let value = if !arg.get_action().takes_values() {
+            toml::Value::Boolean(values.iter().any(|value| value == "true"))
+        } else if matches!(arg.get_action(), clap::ArgAction::Append) {
+            toml::Value::Array(values.into_iter().map(toml::Value::String).collect())
+        } else {
+            toml::Value::String(values.join(","))
+        };
+
+        config.insert(name, value);
+    }
+
+    let mut output = toml::to_string(&config).context("serializing the config")?;
+    for name in omitted {
+        output.push_str(&format!("# {} is left out, set it again\n", name));
+    }
+
+    Ok(output)
+}
+
+pub fn producer(args: &ProduceArgs) -> anyhow::Result<()> {
+    let output = open_data_fd(args.fd, FdDirection::Write)?;
+
+    if args.v4l2_device().is_some() {
+        anyhow::ensure!(
+            args.xid.is_none()
+                && args.window_name.is_none()
+                && args.region.is_none()
+                && args.monitor.is_none()
+                && args.display.is_none()
+                && !args.use_damage
+                && args.show_pointer().is_none(),
+            "window, region, monitor, display, damage and pointer options only apply to the x11 source"
+        );
+    }
+
+    if args.use_damage {
+        tracing::warn!(
+            "damage mode may produce partially updated frames with some downstream elements"
+        );
+    }
+
+    let pipeline = gst::Pipeline::new();
+
+    let region = args.capture_region()?;
+    let mut elements = make_capture_chain(args, region)?;
+
+    if let Some(device) = &args.pip {
+        elements = vec![make_pip_bin(args, elements, device)?];
+    }
+
+    let videoqueue = make_element("queue")?.build()?;
+
+    let fdsink = make_element("fdsink")?.property("fd", args.fd).build()?;
+
+    let dump = args
+        .dump
+        .as_ref()
+        .map(|path| File::create(path).with_context(|| format!("creating {}", path.display())))
+        .transpose()?;
+
+    // where the video is written out, and so where changes get announced
+    let mut written = vec![(fdsink.clone(), output.try_clone()?)];
+
+    // every tee branch needs its own queue, or the sinks deadlock prerolling
+    let dump_branch = match &dump {
+        Some(file) => {
+            let tee = make_element("tee")?.build()?;
+            let dump_sink = make_element("fdsink")?
+                .property("fd", file.as_raw_fd())
+                .build()?;
+            written.push((dump_sink.clone(), file.try_clone()?));
+
+            elements.extend([
+                videoqueue.clone(),
+                tee.clone(),
+                make_element("queue")?.build()?,
+                fdsink,
+            ]);
+
+            vec![tee, make_element("queue")?.build()?, dump_sink]
+        }
+        None => {
+            elements.extend([videoqueue.clone(), fdsink]);
+            vec![]
+        }
+    };
+
+    pipeline
+        .add_many(&elements)
+        .context("adding the producer pipeline")?;
+
+    link_chain("producer pipeline", &elements)?;
+
+    if !dump_branch.is_empty() {
+        pipeline
+            .add_many(&dump_branch[1..])
+            .context("adding the dump branch")?;
+        link_chain("dump branch", &dump_branch)?;
+    }
+
+    if args.print_pipeline {
+        eprintln!("{}", launch_line(&pipeline));
+    }
+
+    if args.dry_run {
+        return dry_run(&pipeline);
+    }
+
+    check_window(args)?;
+
+    let main_loop = glib::MainLoop::new(None, false);
+    let pipeline_error: PipelineError = Default::default();
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout);
+
+    let shutdown = {
+        let pipeline = pipeline.clone();
+        let main_loop = main_loop.clone();
+        let pipeline_error = pipeline_error.clone();
+        let already_exited = Arc::new(AtomicBool::new(false));
+
+        move || {
+            if !already_exited.swap(true, Ordering::Relaxed) {
+                // send EOS wait for the pipeline to send EOS
+                pipeline.send_event(gst::event::Eos::new());
+                arm_shutdown_deadline(&main_loop, &pipeline_error, shutdown_timeout);
+            }
+        }
+    };
+
+    let mut sources: Vec<_> = [Signal::SIGTERM, Signal::SIGINT]
+        .into_iter()
+        .map(|signal| {
+            let shutdown = shutdown.clone();
+
+            glib::unix_signal_add(signal as i32, move || {
+                tracing::debug!(%signal, "received signal");
+                shutdown();
+                glib::ControlFlow::Continue
+            })
+        })
+        .collect();
+
+    let last_heartbeat = Arc::new(Mutex::new(Instant::now()));
+    if args.heartbeat_timeout > 0 {
+        let timeout = Duration::from_secs(args.heartbeat_timeout);
+        let last_heartbeat = last_heartbeat.clone();
+
+        sources.push(glib::timeout_add(timeout, move || {
+            let elapsed = last_heartbeat.lock().unwrap().elapsed();
+            if elapsed > timeout {
+                tracing::warn!(
+                    ?elapsed,
+                    "no heartbeat from downstream, the link may be dead"
+                );
+            }
+
+            glib::ControlFlow::Continue
+        }));
+    }
+
+    // the receiver drives us through control messages written to our stdin
+    std::thread::spawn({
+        let shutdown = shutdown.clone();
+
+        move || {
+            let mut stdin = std::io::stdin();
+
+            loop {
+                let message = match ControlMessage::read_from(&mut stdin) {
+                    Ok(Some(message)) => message,
+                    Ok(None) => {
+                        tracing::warn!(
+                            "downstream disconnected without asking to stop, the receiver \
+                             died or the qrexec link broke"
+                        );
+                        shutdown();
+                        return;
+                    }
+                    Err(err) => {
+                        tracing::error!(?err, "failed to read control message from downstream");
+                        shutdown();
+                        return;
+                    }
+                };
+
+                tracing::trace!(?message, "received control message from downstream");
+                *last_heartbeat.lock().unwrap() = Instant::now();
+
+                match message {
+                    ControlMessage::Stop => {
+                        tracing::info!("downstream asked to stop");
+                        shutdown();
+                        return;
+                    }
+                    ControlMessage::Heartbeat => (),
+                }
+            }
+        }
+    });
+
+    let bus = pipeline.bus().context("gstreamer pipeline without bus")?;
+    let _bus_watch = bus
+        .add_watch({
+            let main_loop = main_loop.clone();
+            let pipeline_error = pipeline_error.clone();
+            let pipeline = pipeline.clone();
+            let dump_dot_dir = args.dump_dot.clone();
+
+            move |_, msg| {
+                match msg.view() {
+                    MessageView::Eos(..) => {
+                        tracing::debug!("gstreamer reach EOS");
+                        main_loop.quit();
+                    }
+                    MessageView::StateChanged(state_changed)
+                        if msg.src() == Some(pipeline.upcast_ref()) =>
+                    {
+                        tracing::debug!(
+                            old = ?state_changed.old(),
+                            current = ?state_changed.current(),
+                            pending = ?state_changed.pending(),
+                            "pipeline state changed"
+                        );
+
+                        if state_changed.current() == gst::State::Playing {
+                            if let Some(dir) = &dump_dot_dir {
+                                dump_dot(&pipeline, dir, "producer-playing");
+                            }
+                        }
+                    }
+                    MessageView::Error(err) => {
+                        let err = bus_error(msg, &err);
+                        tracing::error!("{:#}", err);
+
+                        if let Some(dir) = &dump_dot_dir {
+                            dump_dot(&pipeline, dir, "producer-error");
+                        }
+
+                        pipeline_error.lock().unwrap().get_or_insert(err);
+                        main_loop.quit();
+                    }
+                    MessageView::Warning(warning) => {
+                        tracing::warn!("{}", bus_warning(msg, &warning));
+                    }
+                    _ => (),
+                }
+
+                glib::ControlFlow::Continue
+            }
+        })
+        .context("watching pipeline bus")?;
+
+    announce_videoinfo(
+        args,
+        region,
+        &videoqueue
+            .static_pad("sink")
+            .context("queue without sink pad")?,
+        output,
+        dump.as_ref()
+            .map(File::try_clone)
+            .transpose()
+            .context("duplicating the dump file")?,
+        &main_loop,
+        &pipeline_error,
+    )?;
+
+    let fallback_framerate = gst::Fraction::new(args.fallback_framerate, 1);
+    for (sink, file) in written {
+        announce_caps_changes(
+            &sink.static_pad("sink").context("fdsink without sink pad")?,
+            file,
+            fallback_framerate,
+            &main_loop,
+            &pipeline_error,
+        )?;
+    }
+
+    play(&pipeline)?;
+
+    main_loop.run();
+
+    for source in sources {
+        source.remove();
+    }
+
+    tracing::debug!("finishing pipeline");
+    pipeline.set_state(gst::State::Null)?;
+
+    match pipeline_error.lock().unwrap().take() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Bring the pipeline to Ready, which creates and checks all elements without
+/// negotiating or touching the display and network, log its topology and tear it down
+fn dry_run(pipeline: &gst::Pipeline) -> anyhow::Result<()> {
+    pipeline
+        .set_state(gst::State::Ready)
+        .context("readying pipeline")?;
+
+    for element in pipeline.children() {
+        for pad in element.src_pads() {
+            let Some(peer) = pad.peer() else {
+                tracing::info!("{}:{} -> (unlinked)", element.name(), pad.name());
+                continue;
+            };
+
+            tracing::info!(
+                "{}:{} -> {}:{}",
+                element.name(),
+                pad.name(),
+                peer.parent_element()
+                    .map(|parent| parent.name().to_string())
+                    .unwrap_or_default(),
+                peer.name()
+            );
+        }
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+    tracing::info!("dry run succeeded");
+
+    Ok(())
+}
+
+/// Write the pipeline graph to `<dir>/<timestamp>-<name>.dot`, it can be
+/// rendered with `dot -Tsvg`
+fn dump_dot(pipeline: &gst::Pipeline, dir: &Path, name: &str) {
+    let path = dir.join(format!(
+        "{}-{}.dot",
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S"),
+        name
+    ));
+    match std::fs::write(&path, dot_graph(pipeline)) {
+        Ok(()) => tracing::info!(path = %path.display(), "wrote pipeline graph"),
+        Err(err) => tracing::warn!(?err, path = %path.display(), "failed to write pipeline graph"),
+    }
+}
+
+/// Properties rtmp2sink fills in from its location, the stream key among them
+const RTMP_SECRET_PROPERTIES: &[&str] = &["stream", "username", "password"];
+
+/// Graph of `pipeline` in the DOT format, with the stream keys and credentials
+/// masked out of the element properties it lists
+fn dot_graph(pipeline: &gst::Pipeline) -> String {
+    let graph = gst::debug_bin_to_dot_data(pipeline, gst::DebugGraphDetails::all());
+    let mut graph = redact_rtmp_urls(&graph);
+
+    // the key shows on its own in the stream property, not only in the location
+    let mut elements = Vec::new();
+    collect_elements(pipeline.upcast_ref(), &mut elements);
+    for element in &elements {
+        if !element
+            .factory()
+            .is_some_and(|factory| factory.name() == "rtmp2sink")
+        {
+            continue;
+        }
+
+        for name in RTMP_SECRET_PROPERTIES {
+            let Some(secret) = element.property::<Option<String>>(name) else {
+                continue;
+            };
+            if !secret.is_empty() {
+                graph = graph.replace(&secret, "<redacted>");
+            }
+        }
+    }
+
+    graph
+}
+
+/// `pipeline` as a gst-launch-1.0 command line, quoted for a POSIX shell: every
+/// element with the properties changed from their defaults, bins flattened,
+/// then every link between them
+fn launch_line(pipeline: &gst::Pipeline) -> String {
+    let mut elements = Vec::new();
+    collect_elements(pipeline.upcast_ref(), &mut elements);
+
+    let mut words = vec!["gst-launch-1.0".to_owned()];
+
+    for element in &elements {
+        let Some(factory) = element.factory() else {
+            continue;
+        };
+
+        words.push(factory.name().to_string());
+        words.push(format!("name={}", element.name()));
+        let is_rtmp2sink = factory.name() == "rtmp2sink";
+
+        for pspec in element.list_properties() {
+            let flags = pspec.flags();
+            if !flags.contains(glib::ParamFlags::READWRITE) || pspec.name() == "name" {
+                continue;
+            }
+
+            let value = element.property_value(pspec.name());
+            // objects and the like can't be given on a launch line
+            let (Ok(value), Ok(default)) = (value.serialize(), pspec.default_value().serialize())
+            else {
+                continue;
+            };
+            if value == default {
+                continue;
+            }
+
+            if is_rtmp2sink && RTMP_SECRET_PROPERTIES.contains(&pspec.name()) {
+                words.push(launch_property(pspec.name(), "<redacted>"));
+            } else {
+                words.push(launch_property(pspec.name(), &value));
+            }
+        }
+    }
+
+    for element in &elements {
+        for srcpad in element.src_pads() {
+            let Some(sinkpad) = linked_element_pad(&srcpad) else {
+                continue;
+            };
+            let Some(peer) = sinkpad.parent_element() else {
+                continue;
+            };
+
+            words.push(format!("{}.{}", element.name(), srcpad.name()));
+            words.push("!".to_owned());
+            words.push(format!("{}.{}", peer.name(), sinkpad.name()));
+        }
+    }
+
+    // the key is in the rtmp2sink location as well
+    redact_rtmp_urls(&words.join(" "))
+}
+
+/// Every element within `bin` that isn't a bin itself, in the order they were added
+fn collect_elements(bin: &gst::Bin, elements: &mut Vec<gst::Element>) {
+    for child in bin.children().into_iter().rev() {
+        match child.downcast_ref::<gst::Bin>() {
+            Some(bin) => collect_elements(bin, elements),
+            None => elements.push(child),
+        }
+    }
+}
+
+/// Sink pad of the element `srcpad` feeds, seeing through the ghost pads of bins
+fn linked_element_pad(srcpad: &gst::Pad) -> Option<gst::Pad> {
+    let mut pad = srcpad.peer()?;
+
+    loop {
+        // into a bin, through its sink ghost pad
+        if let Some(ghost) = pad.downcast_ref::<gst::GhostPad>() {
+            pad = ghost.target()?;
+            continue;
+        }
+
+        // out of a bin, from the inner side of its src ghost pad
+        match pad
+            .parent()
+            .and_then(|parent| parent.downcast::<gst::GhostPad>().ok())
+        {
+            Some(ghost) => pad = ghost.peer()?,
+            None => return Some(pad),
+        }
+    }
+}
+
+/// `name=value` as a single shell word, quoted when the value needs it
+fn launch_property(name: &str, value: &str) -> String {
+    let plain = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_-+.:/".contains(c));
+
+    if plain {
+        format!("{}={}", name, value)
+    } else {
+        let quoted = value.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("'{}=\"{}\"'", name, quoted.replace('\'', "'\\''"))
+    }
+}
+
+/// First error that made the main loop stop, reported once the pipeline is torn down
+type PipelineError = Arc<Mutex<Option<anyhow::Error>>>;
+
+/// Give the pipeline `timeout` to drain after EOS was sent, then stop the main
+/// loop anyway so a wedged element (e.g. rtmp2sink on a dead socket) can't
+/// keep the process alive forever
+fn arm_shutdown_deadline(
+    main_loop: &glib::MainLoop,
+    pipeline_error: &PipelineError,
+    timeout: Duration,
+) {
+    let main_loop = main_loop.clone();
+    let pipeline_error = pipeline_error.clone();
+
+    glib::timeout_add_once(timeout, move || {
+        tracing::warn!(?timeout, "pipeline did not drain in time, forcing shutdown");
+        pipeline_error.lock().unwrap().get_or_insert_with(|| {
+            anyhow::anyhow!("timed out after {:?} waiting for EOS", timeout)
+        });
+        main_loop.quit();
+    });
+}
+
+/// Name of the object that posted `msg` on the bus
+fn message_source(msg: &gst::Message) -> String {
+    msg.src()
+        .map(|s| String::from(s.to_string()))
+        .unwrap_or_else(|| "None".into())
+}
+
+/// Build an error out of an error message posted on the pipeline bus, naming
+/// the element that failed
+fn bus_error(msg: &gst::Message, err: &gst::message::Error) -> anyhow::Error {
+    // rtmp2sink may mention its location, stream key included
+    anyhow::anyhow!(redact_rtmp_urls(&format!(
+        "Got error from {}: {} ({})",
+        message_source(msg),
+        err.error(),
+        err.debug().unwrap_or_else(|| "".into()),
+    )))
+}
+
+/// Describe a warning message posted on the pipeline bus, naming the element
+/// that raised it
+fn bus_warning(msg: &gst::Message, warning: &gst::message::Warning) -> String {
+    redact_rtmp_urls(&format!(
+        "Got warning from {}: {} ({})",
+        message_source(msg),
+        warning.error(),
+        warning.debug().unwrap_or_else(|| "".into()),
+    ))
+}
+
+/// Mask the stream key of every RTMP URL within `text`
+fn redact_rtmp_urls(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("rtmp") {
+        let (before, url) = rest.split_at(start);
+        let end = url
+            .find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | '<' | '>' | ')'))
+            .unwrap_or(url.len());
+        let (url, after) = url.split_at(end);
+
+        redacted.push_str(before);
+        redacted.push_str(&redact_rtmp_url(url));
+        rest = after;
+    }
+    redacted.push_str(rest);
+
+    redacted
+}
+
+/// Mask everything after the application name of an `rtmp[s]://host/app/key`
+/// URL, where ingests expect the stream key, or the whole path of one without
+/// an application, `rtmp[s]://host/key`. Anything else is left as is
+fn redact_rtmp_url(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.into();
+    };
+    if !scheme.starts_with("rtmp") {
+        return url.into();
+    }
+
+    let mut parts = rest.splitn(3, '/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(host), Some(app), Some(key)) if !key.is_empty() => {
+            format!("{}://{}/{}/<redacted>", scheme, host, app)
+        }
+        (Some(host), Some(key), None) if !key.is_empty() => {
+            format!("{}://{}/<redacted>", scheme, host)
+        }
+        _ => url.into(),
+    }
+}
+
+/// Buffers `element` dropped since its previous QoS message, out of the total
+/// it reports, remembering the total in `last`
+fn qos_dropped_since(last: &mut BTreeMap<String, u64>, element: String, dropped: u64) -> u64 {
+    let previous = last.insert(element, dropped).unwrap_or(0);
+    dropped.saturating_sub(previous)
+}
+
+/// Counters updated by a buffer probe on the encoder output
+#[derive(Default)]
+struct EncoderStats {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+    /// Buffers dropped anywhere in the pipeline, summed from the totals QoS
+    /// messages report per element
+    dropped: AtomicU64,
+    /// Running time of the last encoded video and audio, in ns, 0 until the
+    /// first buffer
+    video_running_time: AtomicU64,
+    audio_running_time: AtomicU64,
+}
+
+impl EncoderStats {
+    /// How far the encoded video is ahead of the encoded audio, in ms. It
+    /// jitters by about a buffer of either, a steady growth means drift
+    fn av_offset_ms(&self) -> Option<i64> {
+        let video = self.video_running_time.load(Ordering::Relaxed);
+        let audio = self.audio_running_time.load(Ordering::Relaxed);
+
+        (video > 0 && audio > 0).then(|| (video as i64 - audio as i64) / 1_000_000)
+    }
+}
+
+/// Track the running time of the encoded video and audio going into
+/// `videotee` and `audiotee`, for `EncoderStats::av_offset_ms`
+fn watch_av_offset(
+    stats: &Arc<EncoderStats>,
+    videotee: &gst::Element,
+    audiotee: &gst::Element,
+) -> anyhow::Result<()> {
+    for (tee, is_video) in [(videotee, true), (audiotee, false)] {
+        let stats = stats.clone();
+
+        tee.static_pad("sink")
+            .context("tee without sink pad")?
+            .add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+                let running_time = info.buffer().and_then(|buffer| {
+                    let event = pad.sticky_event::<gst::event::Segment>(0)?;
+                    let segment = event.segment().downcast_ref::<gst::ClockTime>()?;
+                    segment.to_running_time(buffer.pts()?)
+                });
+
+                if let Some(running_time) = running_time {
+                    let last = if is_video {
+                        &stats.video_running_time
+                    } else {
+                        &stats.audio_running_time
+                    };
+                    last.store(running_time.nseconds(), Ordering::Relaxed);
+                }
+
+                gst::PadProbeReturn::Ok
+            });
+    }
+
+    Ok(())
+}
+
+/// Count the frames and bytes leaving the encoder
+fn watch_encoder_output(videoenc: &gst::Element) -> anyhow::Result<Arc<EncoderStats>> {
+    let stats = Arc::new(EncoderStats::default());
+
+    videoenc
+        .static_pad("src")
+        .context("encoder without src pad")?
+        .add_probe(gst::PadProbeType::BUFFER, {
+            let stats = stats.clone();
+
+            move |_, info| {
+                if let Some(gst::PadProbeData::Buffer(buffer)) = &info.data {
+                    stats.frames.fetch_add(1, Ordering::Relaxed);
+                    stats
+                        .bytes
+                        .fetch_add(buffer.size() as u64, Ordering::Relaxed);
+                }
+
+                gst::PadProbeReturn::Ok
+            }
+        });
+
+    Ok(stats)
+}
+
+/// Check PulseAudio accepts connections by opening a standalone `pulsesrc`,
+/// retrying `retries` times `delay` apart
+fn wait_for_pulse(retries: u32, delay: Duration) -> bool {
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            std::thread::sleep(delay);
+        }
+
+        let Ok(pulsesrc) = gst::ElementFactory::make("pulsesrc").build() else {
+            return false;
+        };
+
+        let ready = pulsesrc.set_state(gst::State::Ready).is_ok();
+        let _ = pulsesrc.set_state(gst::State::Null);
+
+        if ready {
+            return true;
+        }
+
+        tracing::warn!(attempt, retries, ?delay, "PulseAudio not ready yet");
+    }
+
+    false
+}
+
+/// Stop the main loop with an error once no buffer left the encoder for `timeout`
+fn watch_for_stall(
+    videoenc: &gst::Element,
+    timeout: Duration,
+    main_loop: &glib::MainLoop,
+    pipeline_error: &PipelineError,
+) -> anyhow::Result<glib::SourceId> {
+    let last_buffer = Arc::new(Mutex::new(Instant::now()));
+
+    videoenc
+        .static_pad("src")
+        .context("encoder without src pad")?
+        .add_probe(gst::PadProbeType::BUFFER, {
+            let last_buffer = last_buffer.clone();
+
+            move |_, _| {
+                *last_buffer.lock().unwrap() = Instant::now();
+                gst::PadProbeReturn::Ok
+            }
+        });
+
+    let main_loop = main_loop.clone();
+    let pipeline_error = pipeline_error.clone();
+
+    Ok(glib::timeout_add(Duration::from_secs(1), move || {
+        let stalled_for = last_buffer.lock().unwrap().elapsed();
+
+        if stalled_for >= timeout {
+            send_control(ControlMessage::Stop);
+            tracing::error!(
+                ?stalled_for,
+                "no video left the encoder, the pipeline stalled"
+            );
+            pipeline_error.lock().unwrap().get_or_insert_with(|| {
+                anyhow::anyhow!("pipeline stalled, no encoded video for {:?}", stalled_for)
+            });
+            main_loop.quit();
+        }
+
+        glib::ControlFlow::Continue
+    }))
+}
+
+/// Feed the encoder the last frame over and over while the returned flag is
+/// set, hiding the screen without stopping the stream: the repeated frame takes
+/// the timestamps of the frames it replaces, so the outputs never notice
+fn freeze_frame_on_demand(videoenc: &gst::Element) -> anyhow::Result<Arc<AtomicBool>> {
+    let frozen = Arc::new(AtomicBool::new(false));
+    let last_frame = Mutex::new(None::<gst::Buffer>);
+
+    videoenc
+        .static_pad("sink")
+        .context("encoder without sink pad")?
+        .add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+            {
+                let frozen = frozen.clone();
+
+                move |_, info| {
+                    match &mut info.data {
+                        // a frame of the previous size would no longer fit
+                        Some(gst::PadProbeData::Event(event)) => {
+                            if let gst::EventView::Caps(..) = event.view() {
+                                *last_frame.lock().unwrap() = None;
+                            }
+                        }
+                        Some(gst::PadProbeData::Buffer(buffer)) => {
+                            let mut last_frame = last_frame.lock().unwrap();
+
+                            match &*last_frame {
+                                Some(last_frame) if frozen.load(Ordering::Relaxed) => {
+                                    let mut frame = last_frame.copy();
+                                    {
+                                        let frame = frame.make_mut();
+                                        frame.set_pts(buffer.pts());
+                                        frame.set_dts(buffer.dts());
+                                        frame.set_duration(buffer.duration());
+                                    }
+                                    *buffer = frame;
+                                }
+                                // while frozen, the first frame after a caps
+                                // change is the one repeated from then on
+                                _ => *last_frame = Some(buffer.clone()),
+                            }
+                        }
+                        _ => (),
+                    }
+
+                    gst::PadProbeReturn::Ok
+                }
+            },
+        );
+
+    Ok(frozen)
+}
+
+/// Destination of the stats as line-delimited JSON
+struct StatsJson {
+    writer: Box<dyn Write + Send>,
+    outputs: Arc<Outputs>,
+    rtmp_destinations: Vec<gst::Bin>,
+}
+
+impl StatsJson {
+    /// Connect to `path` if it's a unix socket, otherwise append to it
+    fn open(
+        path: &Path,
+        outputs: Arc<Outputs>,
+        rtmp_destinations: Vec<gst::Bin>,
+    ) -> anyhow::Result<Self> {
+        let is_socket =
+            std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+
+        let writer: Box<dyn Write + Send> = if is_socket {
+            Box::new(
+                UnixStream::connect(path)
+                    .with_context(|| format!("connecting to {}", path.display()))?,
+            )
+        } else {
+            Box::new(
+                File::options()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("opening {}", path.display()))?,
+            )
+        };
+
+        Ok(StatsJson {
+            writer,
+            outputs,
+            rtmp_destinations,
+        })
+    }
+
+    fn write(
+        &mut self,
+        fps: f64,
+        kbps: f64,
+        dropped: u64,
+        av_offset_ms: Option<i64>,
+        queues: &[gst::Element],
+    ) -> std::io::Result<()> {
+        let queues: serde_json::Map<_, _> = queues
+            .iter()
+            .map(|queue| {
+                let level = serde_json::json!({
+                    "buffers": queue.property::<u32>("current-level-buffers"),
+                    "bytes": queue.property::<u32>("current-level-bytes"),
+                    "time_ms": queue.property::<u64>("current-level-time") / 1_000_000,
+                });
+
+                (queue.name().to_string(), level)
+            })
+            .collect();
+
+        let rtmp_connected = self
+            .rtmp_destinations
+            .iter()
+            .any(|bin| self.outputs.is_running(bin));
+
+        let line = serde_json::json!({
+            "timestamp": chrono::Local::now().to_rfc3339(),
+            "fps": fps,
+            "kbps": kbps,
+            "dropped": dropped,
+            "av_offset_ms": av_offset_ms,
+            "rtmp_connected": rtmp_connected,
+            "queues": queues,
+        });
+
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()
+    }
+}
+
+/// Periodically log the encoder fps and bitrate along with the fill level of
+/// `queues`, and write them to `json` if given
+fn report_stats(
+    interval: Duration,
+    stats: Arc<EncoderStats>,
+    queues: Vec<gst::Element>,
+    mut json: Option<StatsJson>,
+) -> glib::SourceId {
+    let mut last_report = Instant::now();
+
+    glib::timeout_add(interval, move || {
+        let elapsed = last_report.elapsed().as_secs_f64();
+        last_report = Instant::now();
+
+        let frames = stats.frames.swap(0, Ordering::Relaxed);
+        let bytes = stats.bytes.swap(0, Ordering::Relaxed);
+        let dropped = stats.dropped.swap(0, Ordering::Relaxed);
+        let av_offset_ms = stats.av_offset_ms();
+
+        let fps = frames as f64 / elapsed;
+        let kbps = bytes as f64 * 8.0 / 1000.0 / elapsed;
+
+        tracing::info!(
+            fps = %format!("{:.1}", fps),
+            kbps = %format!("{:.0}", kbps),
+            dropped,
+            ?av_offset_ms,
+            "encoder stats"
+        );
+
+        for queue in &queues {
+            tracing::info!(
+                queue = %queue.name(),
+                buffers = queue.property::<u32>("current-level-buffers"),
+                bytes = queue.property::<u32>("current-level-bytes"),
+                time = %gst::ClockTime::from_nseconds(queue.property::<u64>("current-level-time")),
+                "queue level"
+            );
+        }
+
+        // a consumer going away shouldn't take the stream down with it
+        if let Some(writer) = &mut json {
+            if let Err(err) = writer.write(fps, kbps, dropped, av_offset_ms, &queues) {
+                tracing::warn!(%err, "failed to write JSON stats, no longer writing them");
+                json = None;
+            }
+        }
+
+        glib::ControlFlow::Continue
+    })
+}
+
+/// Log the latency of `pipeline` as answered by a latency query, along with
+/// the configured depth of `queues`
+fn report_latency(pipeline: &gst::Pipeline, queues: &[gst::Element]) {
+    let mut query = gst::query::Latency::new();
+    if !pipeline.query(&mut query) {
+        tracing::debug!("latency query not answered");
+        return;
+    }
+
+    let (live, min, max) = query.result();
+    tracing::info!(live, %min, ?max, "pipeline latency");
+
+    for queue in queues {
+        tracing::info!(
+            queue = %queue.name(),
+            max_buffers = queue.property::<u32>("max-size-buffers"),
+            max_bytes = queue.property::<u32>("max-size-bytes"),
+            max_time = %gst::ClockTime::from_nseconds(queue.property::<u64>("max-size-time")),
+            "queue depth"
+        );
+    }
+}
+
+/// Periodically steer the encoder bitrate within `min_kbps..=max_kbps` from
+/// the fill level of `queue`, feeding the network: back off quickly once it
+/// holds more than half its capacity, and creep back up while it's nearly empty
+fn adapt_bitrate(
+    encoder: Encoder,
+    videoenc: gst::Element,
+    queue: gst::Element,
+    min_kbps: u32,
+    max_kbps: u32,
+) -> glib::SourceId {
+    let capacity = queue.property::<u64>("max-size-time");
+
+    glib::timeout_add(Duration::from_secs(1), move || {
+        let level = queue.property::<u64>("current-level-time");
+        let bitrate = encoder.bitrate_kbps(&videoenc);
+
+        let target = if level > capacity / 2 {
+            bitrate - bitrate / 5
+        } else if level < capacity / 10 {
+            bitrate + bitrate / 20 + 1
+        } else {
+            bitrate
+        }
+        .clamp(min_kbps, max_kbps);
+
+        if target != bitrate {
+            tracing::info!(
+                from_kbps = bitrate,
+                to_kbps = target,
+                queued = %gst::ClockTime::from_nseconds(level),
+                "adapting encoder bitrate"
+            );
+            encoder.set_bitrate_kbps(&videoenc, target);
+        }
+
+        glib::ControlFlow::Continue
+    })
+}
+
+/// Set a property from its string form, failing instead of panicking when the
+/// element lacks it or the value doesn't parse, since encoder properties vary
+/// across plugin versions
+fn try_set_property_from_str(
+    element: &gst::Element,
+    name: &str,
+    value: &str,
+) -> anyhow::Result<()> {
+    let pspec = element
+        .find_property(name)
+        .with_context(|| format!("{} has no property {}", element.name(), name))?;
+
+    let value = glib::Value::deserialize(value, pspec.value_type())
+        .with_context(|| format!("invalid value {:?} for {}:{}", value, element.name(), name))?;
+    element.set_property_from_value(name, &value);
+
+    Ok(())
+}
+
+/// Set an enum property from the nick of one of its values, listing the valid
+/// nicks when `nick` isn't one of them
+fn set_enum_property(element: &gst::Element, name: &str, nick: &str) -> anyhow::Result<()> {
+    let pspec = element
+        .find_property(name)
+        .with_context(|| format!("{} has no property {}", element.name(), name))?;
+    let class = glib::EnumClass::with_type(pspec.value_type())
+        .with_context(|| format!("{}:{} is not an enum", element.name(), name))?;
+
+    if class.value_by_nick(nick).is_none() {
+        let nicks: Vec<_> = class.values().iter().map(|value| value.nick()).collect();
+        anyhow::bail!(
+            "invalid {} {:?} for {}, expected one of {}",
+            name,
+            nick,
+            element.name(),
+            nicks.join(", ")
+        );
+    }
+
+    element.set_property_from_str(name, nick);
+    Ok(())
+}
+
+/// An element GStreamer can't make, almost always because the plugin providing
+/// it isn't installed, as is common in minimal templates
+#[derive(Debug, thiserror::Error)]
+#[error("GStreamer element {factory_name} is missing, {}", install_hint(.factory_name))]
+pub struct MissingElement {
+    factory_name: String,
+}
+
+impl MissingElement {
+    fn new(factory_name: &str) -> Self {
+        Self {
+            factory_name: factory_name.to_string(),
+        }
+    }
+}
+
+/// GStreamer plugin providing the `factory_name` element and the module it is
+/// distributed in, which distributions package on their own
+fn plugin_providing(factory_name: &str) -> Option<(&'static str, &'static str)> {
+    let plugin = match factory_name {
+        "capsfilter" | "fakesink" | "fakesrc" | "fdsink" | "fdsrc" | "identity" | "queue"
+        | "tee" => ("coreelements", "gstreamer"),
+        "audioconvert" => ("audioconvert", "gst-plugins-base"),
+        "audiorate" => ("audiorate", "gst-plugins-base"),
+        "audioresample" => ("audioresample", "gst-plugins-base"),
+        "audiotestsrc" => ("audiotestsrc", "gst-plugins-base"),
+        "compositor" => ("compositor", "gst-plugins-base"),
+        "opusenc" => ("opus", "gst-plugins-base"),
+        "rawvideoparse" => ("rawparse", "gst-plugins-base"),
+        "textoverlay" | "clockoverlay" | "timeoverlay" => ("pango", "gst-plugins-base"),
+        "videoconvert" | "videoscale" => ("videoconvertscale", "gst-plugins-base"),
+        "videorate" => ("videorate", "gst-plugins-base"),
+        "videotestsrc" => ("videotestsrc", "gst-plugins-base"),
+        "audiocheblimit" => ("audiofx", "gst-plugins-good"),
+        "equalizer-10bands" => ("equalizer", "gst-plugins-good"),
+        "flvmux" => ("flv", "gst-plugins-good"),
+        "matroskamux" | "webmmux" => ("matroska", "gst-plugins-good"),
+        "mp4mux" => ("isomp4", "gst-plugins-good"),
+        "pulsesrc" => ("pulseaudio", "gst-plugins-good"),
+        "splitmuxsink" => ("multifile", "gst-plugins-good"),
+        "v4l2src" => ("video4linux2", "gst-plugins-good"),
+        "videocrop" => ("videocrop", "gst-plugins-good"),
+        "vp9enc" => ("vpx", "gst-plugins-good"),
+        "ximagesrc" => ("ximagesrc", "gst-plugins-good"),
+        "fdkaacenc" => ("fdkaac", "gst-plugins-bad"),
+        "h264parse" | "h265parse" => ("videoparsersbad", "gst-plugins-bad"),
+        "hlssink2" => ("hls", "gst-plugins-bad"),
+        "nvh264enc" | "nvh265enc" => ("nvcodec", "gst-plugins-bad"),
+        "openh264enc" => ("openh264", "gst-plugins-bad"),
+        "rtmp2sink" => ("rtmp2", "gst-plugins-bad"),
+        "x265enc" => ("x265", "gst-plugins-bad"),
+        _ => return None,
+    };
+
+    Some(plugin)
+}
+
+fn install_hint(factory_name: &str) -> String {
+    match plugin_providing(factory_name) {
+        Some((plugin, module)) => format!(
+            "install the package shipping the {} plugin from {}",
+            plugin, module
+        ),
+        None => "check the plugin providing it is installed".to_string(),
+    }
+}
+
+/// Start building a `factory_name` element, failing with what to install when
+/// GStreamer doesn't know it, where building would only say it failed
+fn make_element(factory_name: &str) -> Result<gst::ElementBuilder<'_>, MissingElement> {
+    if gst::ElementFactory::find(factory_name).is_none() {
+        return Err(MissingElement::new(factory_name));
+    }
+
+    Ok(gst::ElementFactory::make(factory_name))
+}
+
+/// Queue in front of an output branch, kept short with `low_latency` so a
+/// slow output drops old data rather than delaying everything
+fn make_output_queue(low_latency: bool) -> anyhow::Result<gst::Element> {
+    let builder = make_element("queue")?;
+    let builder = if low_latency {
+        builder
+            .property(
+                "max-size-time",
+                gst::ClockTime::from_mseconds(500).nseconds(),
+            )
+            .property_from_str("leaky", "downstream")
+    } else {
+        builder
+    };
+
+    Ok(builder.build()?)
+}
+
+/// How long to wait for the first frame from the producer to negotiate
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bring `pipeline` to Paused, or Playing when live, and wait for `pad` to
+/// carry negotiated caps, failing on the first error posted meanwhile
+fn preflight_caps(pipeline: &gst::Pipeline, pad: &gst::Pad) -> anyhow::Result<gst::Caps> {
+    let change = pipeline
+        .set_state(gst::State::Paused)
+        .context("pausing pipeline")?;
+
+    // live sources don't produce anything until playing
+    if change == gst::StateChangeSuccess::NoPreroll {
+        pipeline
+            .set_state(gst::State::Playing)
+            .context("starting pipeline")?;
+    }
+
+    let bus = pipeline.bus().context("gstreamer pipeline without bus")?;
+    let deadline = Instant::now() + PREFLIGHT_TIMEOUT;
+
+    loop {
+        if let Some(caps) = pad.current_caps() {
+            return Ok(caps);
+        }
+
+        anyhow::ensure!(
+            Instant::now() < deadline,
+            "no caps on {} after {:?}",
+            pad.name(),
+            PREFLIGHT_TIMEOUT
+        );
+
+        let msg = bus.timed_pop_filtered(
+            gst::ClockTime::from_mseconds(100),
+            &[gst::MessageType::Error],
+        );
+
+        if let Some(msg) = msg {
+            if let MessageView::Error(err) = msg.view() {
+                return Err(bus_error(&msg, &err));
+            }
+        }
+    }
+}
+
+/// Element delaying the timestamps of everything passing through by `delay_ms`,
+/// or nothing for no delay
+fn make_delay(delay_ms: i64) -> anyhow::Result<Vec<gst::Element>> {
+    if delay_ms <= 0 {
+        return Ok(vec![]);
+    }
+
+    let delay = make_element("identity")?
+        .property(
+            "ts-offset",
+            i64::try_from(gst::ClockTime::from_mseconds(delay_ms as u64).nseconds())
+                .context("--av-offset out of range")?,
+        )
+        .build()?;
+
+    Ok(vec![delay])
+}
+
+/// Caps scaling the video to `resolution`, with square pixels
+fn scaled_caps(resolution: Resolution) -> gst::Caps {
+    gst::Caps::builder("video/x-raw")
+        .field("width", resolution.width)
+        .field("height", resolution.height)
+        .field("pixel-aspect-ratio", gst::Fraction::new(1, 1))
+        .build()
+}
+
+/// Caps of the raw video the producer announced in `video_info`
+fn raw_video_caps(video_info: &VideoInfo, colorimetry: &str) -> gst::Caps {
+    gst::Caps::builder("video/x-raw")
+        .field("format", &video_info.format)
+        .field("width", video_info.width)
+        .field("height", video_info.height)
+        .field("framerate", video_info.framerate)
+        .field("colorimetry", colorimetry)
+        .build()
+}
+
+/// Make `rawvideoparse` frame the raw video laid out as in `video_info`
+fn configure_rawvideoparse(
+    rawvideoparse: &gst::Element,
+    video_info: &VideoInfo,
+    colorimetry: &str,
+) -> anyhow::Result<()> {
+    if video_info.planes.is_empty() {
+        rawvideoparse.set_property("use-sink-caps", true);
+        return Ok(());
+    }
+
+    // the caps can't describe padded rows, so the layout the producer
+    // announced is given explicitly
+    let to_array = |values: Vec<u32>| -> anyhow::Result<gst::Array> {
+        let values = values
+            .into_iter()
+            .map(i32::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .context("plane layout out of range")?;
+
+        Ok(gst::Array::new(values))
+    };
+
+    rawvideoparse.set_property("use-sink-caps", false);
+    rawvideoparse.set_property(
+        "format",
+        gst_video::VideoFormat::from_string(&video_info.format),
+    );
+    rawvideoparse.set_property("width", video_info.width);
+    rawvideoparse.set_property("height", video_info.height);
+    rawvideoparse.set_property("framerate", video_info.framerate);
+    rawvideoparse.set_property("colorimetry", colorimetry);
+    rawvideoparse.set_property("frame-size", video_info.frame_size);
+    rawvideoparse.set_property(
+        "plane-offsets",
+        to_array(video_info.planes.iter().map(|plane| plane.offset).collect())?,
+    );
+    rawvideoparse.set_property(
+        "plane-strides",
+        to_array(video_info.planes.iter().map(|plane| plane.stride).collect())?,
+    );
+
+    Ok(())
+}
+
+/// Elements configured after the video info header, to follow the producer
+/// when it announces a new one
+struct RawInput {
+    /// Capsfilters around rawvideoparse
+    capsfilters: [gst::Element; 2],
+    rawvideoparse: gst::Element,
+    /// Capsfilter scaling the video, and the resolution the video is fit
+    /// within, unless letterboxed to it
+    scaling: Option<(gst::Element, Option<Resolution>)>,
+    /// --input-colorimetry, overriding the announced one
+    input_colorimetry: Option<String>,
+    /// Splits the raw video at headers sent in between frames, none for the
+    /// dry run which has no frames
+    splitter: Mutex<Option<HeaderSplitter>>,
+}
+
+impl RawInput {
+    /// Parse the raw video from here on as `video_info` describes it
+    fn apply(&self, video_info: &VideoInfo) -> anyhow::Result<()> {
+        check_raw_format(&video_info.format)?;
+        let colorimetry = self
+            .input_colorimetry
+            .as_deref()
+            .unwrap_or(&video_info.colorimetry);
+
+        for capsfilter in &self.capsfilters {
+            capsfilter.set_property("caps", raw_video_caps(video_info, colorimetry));
+        }
+        configure_rawvideoparse(&self.rawvideoparse, video_info, colorimetry)?;
+
+        if let Some((scaleconfig, Some(resolution))) = &self.scaling {
+            let output = resolution.fit(video_info.width, video_info.height);
+            scaleconfig.set_property("caps", scaled_caps(output));
+            tracing::info!(%output, "scaling video");
+        }
+
+        Ok(())
+    }
+
+    /// Follow the headers the producer sends in between frames when its
+    /// capture changes resolution, from the buffers going through `pad`
+    /// towards rawvideoparse. The bytes ahead of a header are passed on first,
+    /// the header is applied once they went through.
+    fn follow_headers(self: &Arc<Self>, pad: &gst::Pad) {
+        let pending = Mutex::new(None::<VideoInfo>);
+        let raw_input = self.clone();
+
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(video_info) = pending.lock().unwrap().take() {
+                raw_input.apply_or_post(&video_info);
+            }
+
+            let Some(buffer) = info.buffer() else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let (pts, dts) = (buffer.pts(), buffer.dts());
+            let Ok(map) = buffer.map_readable() else {
+                return gst::PadProbeReturn::Ok;
+            };
+
+            let mut splitter = raw_input.splitter.lock().unwrap();
+            let Some(splitter) = splitter.as_mut() else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let mut split = splitter.push(&map);
+            drop(map);
+
+            while let Some(video_info) = split.header.take() {
+                if split.bytes.as_ref().is_some_and(|bytes| !bytes.is_empty()) {
+                    *pending.lock().unwrap() = Some(video_info);
+                    break;
+                }
+
+                raw_input.apply_or_post(&video_info);
+                split = splitter.push(&[]);
+            }
+
+            match split.bytes {
+                None => gst::PadProbeReturn::Ok,
+                Some(bytes) if bytes.is_empty() => gst::PadProbeReturn::Drop,
+                Some(bytes) => {
+                    let mut replacement = gst::Buffer::from_mut_slice(bytes);
+                    {
+                        let replacement = replacement.get_mut().unwrap();
+                        replacement.set_pts(pts);
+                        replacement.set_dts(dts);
+                    }
+                    info.data = Some(gst::PadProbeData::Buffer(replacement));
+
+                    gst::PadProbeReturn::Ok
+                }
+            }
+        });
+    }
+
+    /// Apply a header met mid-stream, failing the pipeline when it can't be
+    fn apply_or_post(&self, video_info: &VideoInfo) {
+        tracing::info!(?video_info, "producer changed the video info");
+
+        if let Err(err) = self.apply(video_info) {
+            let msg = gst::message::Error::builder(
+                gst::CoreError::Negotiation,
+                &format!("{:#}", err.context("following the new video info")),
+            )
+            .src(&self.rawvideoparse)
+            .build();
+            let _ = self.rawvideoparse.post_message(msg);
+        }
+    }
+}
+
+/// Raw video to pass on out of a chunk given to `HeaderSplitter::push`
+#[derive(Debug, Default)]
+struct Split {
+    /// Bytes to pass on in place of the chunk, None to pass it unchanged
+    bytes: Option<Vec<u8>>,
+    /// Header met after `bytes`, describing the raw video following it
+    header: Option<VideoInfo>,
+}
+
+/// Finds the video info headers a producer sends in between frames within
+/// the raw video, which arrives in chunks of any size. Only frame boundaries
+/// are checked, for the magic and then a whole valid header, so frame
+/// contents are never mistaken for one.
+struct HeaderSplitter {
+    frame_size: usize,
+    /// Bytes of the current frame already passed on
+    position: usize,
+    /// Bytes from a frame boundary on that may start a header, held back
+    /// until the next chunk tells
+    held: Vec<u8>,
+}
+
+impl HeaderSplitter {
+    fn new(frame_size: NonZeroU32) -> Self {
+        HeaderSplitter {
+            frame_size: frame_size.get() as usize,
+            position: 0,
+            held: Vec::new(),
+        }
+    }
+
+    /// Whether `rest`, starting at a frame boundary, may start a header
+    fn may_be_header(rest: &[u8]) -> bool {
+        let len = rest.len().min(VIDEOINFO_MAGIC.len());
+        rest[..len] == VIDEOINFO_MAGIC[..len]
+    }
+
+    /// Split `chunk` at the first header in it, the bytes after the header
+    /// are held until the next push
+    fn push(&mut self, chunk: &[u8]) -> Split {
+        // fast path, no frame in the chunk may start with a header. An empty
+        // chunk only asks for the held bytes, so it can't pass unchanged
+        if self.held.is_empty() && !chunk.is_empty() {
+            let first = (self.frame_size - self.position) % self.frame_size;
+            let mut boundaries = (first..chunk.len()).step_by(self.frame_size);
+            if !boundaries.any(|at| Self::may_be_header(&chunk[at..])) {
+                self.position = (self.position + chunk.len()) % self.frame_size;
+                return Split::default();
+            }
+        }
+
+        let mut data = std::mem::take(&mut self.held);
+        data.extend_from_slice(chunk);
+
+        let mut bytes = Vec::with_capacity(data.len());
+        let mut at = 0;
+        while at < data.len() {
+            if self.position == 0 && Self::may_be_header(&data[at..]) {
+                let mut rest = &data[at..];
+                match recv_stream_videoinfo(&mut rest) {
+                    Ok(video_info) => {
+                        let consumed = data.len() - at - rest.len();
+                        // never 0 in a header that was received
+                        self.frame_size = video_info.frame_size as usize;
+                        self.held = data[at + consumed..].to_vec();
+
+                        return Split {
+                            bytes: Some(bytes),
+                            header: Some(video_info),
+                        };
+                    }
+                    // the rest of the header is yet to come
+                    Err(ProtocolError::TruncatedHeader(..)) => {
+                        self.held = data[at..].to_vec();
+                        break;
+                    }
+                    // a frame that happens to start like a header
+                    Err(..) => (),
+                }
+            }
+
+            let take = (self.frame_size - self.position).min(data.len() - at);
+            bytes.extend_from_slice(&data[at..at + take]);
+            self.position = (self.position + take) % self.frame_size;
+            at += take;
+        }
+
+        Split {
+            bytes: Some(bytes),
+            header: None,
+        }
+    }
+}
+
+/// Keep streaming when the producer closes the raw video, e.g. after a crash.
+/// Its EOS is held back and `fd` polled for the header of a restarted
+/// producer for up to `timeout`. The parsing is then reconfigured for the new
+/// video, dropping what's left of the old one, and reading resumes. Without a
+/// new producer in time, or once `stopping`, the EOS goes through after all.
+fn survive_producer_restart(
+    video: &VideoBranch,
+    fd: RawFd,
+    timeout: Duration,
+    stopping: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let input = open_data_fd(fd, FdDirection::Read)?;
+
+    // flushing drops the partial frame of the old producer, only the parser
+    // and the queue ahead of it have anything to drop
+    video
+        .rawvideoparse
+        .static_pad("src")
+        .context("rawvideoparse without src pad")?
+        .add_probe(gst::PadProbeType::EVENT_FLUSH, |_, _| {
+            gst::PadProbeReturn::Drop
+        });
+
+    let restart = RestartInput {
+        input: Mutex::new(input),
+        videosrc: video.videosrc.clone(),
+        queue: video.rawvideoparsequeue.clone(),
+        raw_input: video.raw_input.clone(),
+        timeout,
+        stopping: stopping.clone(),
+    };
+    let restart = Arc::new(restart);
+
+    video
+        .videosrc
+        .static_pad("src")
+        .context("fdsrc without src pad")?
+        .add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            let is_eos = matches!(
+                &info.data,
+                Some(gst::PadProbeData::Event(event)) if event.type_() == gst::EventType::Eos
+            );
+            if !is_eos || stopping.load(Ordering::Relaxed) {
+                return gst::PadProbeReturn::Ok;
+            }
+
+            tracing::warn!(
+                ?timeout,
+                "raw video ended, waiting for the producer to restart"
+            );
+
+            // reading blocks, and fdsrc can only be restarted from outside its
+            // own streaming thread
+            let restart = restart.clone();
+            std::thread::spawn(move || restart.run());
+
+            gst::PadProbeReturn::Drop
+        });
+
+    Ok(())
+}
+
+/// State for resuming the raw video from a restarted producer
+struct RestartInput {
+    input: Mutex<File>,
+    videosrc: gst::Element,
+    queue: gst::Element,
+    raw_input: Arc<RawInput>,
+    timeout: Duration,
+    stopping: Arc<AtomicBool>,
+}
+
+impl RestartInput {
+    fn run(&self) {
+        let result = self
+            .wait_for_producer()
+            .and_then(|video_info| self.resume(&video_info));
+
+        if let Err(err) = result {
+            tracing::error!("{:#}", err);
+
+            // finally let the raw video end, the stream then stops as it would have
+            if let Some(sinkpad) = self.queue.static_pad("sink") {
+                sinkpad.send_event(gst::event::Eos::new());
+            }
+        }
+    }
+
+    /// Poll for a new header until `timeout`, as the fd reads as closed until
+    /// a producer writes to it again
+    fn wait_for_producer(&self) -> anyhow::Result<VideoInfo> {
+        let deadline = Instant::now() + self.timeout;
+        let mut input = self.input.lock().unwrap();
+
+        loop {
+            anyhow::ensure!(
+                !self.stopping.load(Ordering::Relaxed),
+                "stopping, not waiting for the producer anymore"
+            );
+
+            match recv_stream_videoinfo(&mut *input) {
+                Ok(video_info) => return Ok(video_info),
+                Err(ProtocolError::TruncatedHeader(err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    anyhow::ensure!(
+                        Instant::now() < deadline,
+                        "the producer didn't restart within {:?}",
+                        self.timeout
+                    );
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+                Err(err) => {
+                    return Err(anyhow::Error::new(err)
+                        .context("receiving video info from the restarted producer"));
+                }
+            }
+        }
+    }
+
+    fn resume(&self, video_info: &VideoInfo) -> anyhow::Result<()> {
+        tracing::info!(?video_info, "producer restarted");
+
+        let queue_sinkpad = self
+            .queue
+            .static_pad("sink")
+            .context("queue without sink pad")?;
+        queue_sinkpad.send_event(gst::event::FlushStart::new());
+        queue_sinkpad.send_event(gst::event::FlushStop::new(false));
+
+        // the new producer starts over at a frame boundary
+        *self.raw_input.splitter.lock().unwrap() =
+            NonZeroU32::new(video_info.frame_size).map(HeaderSplitter::new);
+        self.raw_input.apply(video_info)?;
+
+        // fdsrc stopped its task on EOS, cycling it through Ready starts a new one
+        self.videosrc
+            .set_state(gst::State::Ready)
+            .context("stopping fdsrc")?;
+        self.videosrc
+            .sync_state_with_parent()
+            .context("restarting fdsrc")?;
+
+        Ok(())
+    }
+}
+
+/// Fail clearly when rawvideoparse can't frame the raw `format` the producer
+/// announced, rather than deep within the pipeline once data flows
+fn check_raw_format(format: &str) -> anyhow::Result<()> {
+    let factory = gst::ElementFactory::find("rawvideoparse")
+        .ok_or_else(|| MissingElement::new("rawvideoparse"))?;
+
+    let mut formats = Vec::new();
+    for template in factory.static_pad_templates() {
+        if template.direction() != gst::PadDirection::Src {
+            continue;
+        }
+
+        let caps = template.caps();
+        for structure in caps.iter() {
+            if let Ok(list) = structure.get::<gst::List>("format") {
+                formats.extend(list.iter().filter_map(|value| value.get::<String>().ok()));
+            } else if let Ok(format) = structure.get::<String>("format") {
+                formats.push(format);
+            }
+        }
+    }
+
+    anyhow::ensure!(
+        formats.iter().any(|supported| supported == format),
+        "the producer sends {} video, which rawvideoparse can't parse, expected one of {}",
+        format,
+        formats.join(", ")
+    );
+
+    Ok(())
+}
+
+/// Text overlay element `factory_name` drawn in a `position` corner, shaded so
+/// it stays readable over any content
+fn make_overlay(
+    factory_name: &str,
+    position: OverlayPosition,
+    font_size: u32,
+) -> anyhow::Result<gst::Element> {
+    let (halignment, valignment) = position.alignment();
+
+    Ok(make_element(factory_name)?
+        .property_from_str("halignment", halignment)
+        .property_from_str("valignment", valignment)
+        .property("font-desc", format!("Sans {}", font_size))
+        .property("shaded-background", true)
+        .build()?)
+}
+
+/// Element letting buffers through only once the clock reaches their running time
+fn make_sync_point() -> anyhow::Result<gst::Element> {
+    Ok(make_element("identity")?.property("sync", true).build()?)
+}
+
+/// Set how long the aggregator based `muxer` waits for data on all of its
+/// inputs before muxing without the late ones
+fn set_mux_latency(muxer: &gst::Element, ms: u64) -> anyhow::Result<()> {
+    let factory = muxer.factory().map(|factory| factory.name());
+    anyhow::ensure!(
+        muxer.find_property("latency").is_some(),
+        "{} has no latency to set",
+        factory.as_deref().unwrap_or("muxer")
+    );
+
+    muxer.set_property("latency", gst::ClockTime::from_mseconds(ms).nseconds());
+    tracing::debug!(muxer = %muxer.name(), latency_ms = ms, "set muxer latency");
+
+    Ok(())
+}
+
+/// Warn whenever the decoding timestamps out of `muxer` go backwards, which
+/// ingests report as an unstable stream
+fn watch_muxed_timestamps(muxer: &gst::Element) -> anyhow::Result<()> {
+    let last_dts = Mutex::new(None);
+
+    muxer
+        .static_pad("src")
+        .with_context(|| format!("{} without src pad", muxer.name()))?
+        .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            let dts = match &info.data {
+                Some(gst::PadProbeData::Buffer(buffer)) => buffer.dts_or_pts(),
+                _ => None,
+            };
+
+            // the FLV header and metadata aren't timestamped
+            if let Some(dts) = dts {
+                let last = last_dts.lock().unwrap().replace(dts);
+                if let Some(last) = last.filter(|&last| dts < last) {
+                    tracing::warn!(%dts, %last, "muxed timestamps went backwards");
+                }
+            }
+
+            gst::PadProbeReturn::Ok
+        });
+
+    Ok(())
+}
+
+/// Number of frames in `keyframe_interval` seconds at `framerate`
+fn gop_size(keyframe_interval: u32, framerate: gst::Fraction) -> u32 {
+    let frames = keyframe_interval as f64 * framerate.numer() as f64 / framerate.denom() as f64;
+    (frames.round() as u32).max(1)
+}
+
+/// Name of the local recording file, timestamped so rotated recordings don't clash
+fn recording_file_name(file_extension: &str) -> String {
+    chrono::Local::now()
+        .format(&format!("%Y-%m-%d_%H-%M-%S.stream.{}", file_extension))
+        .to_string()
+}
+
+/// Close the current recording and continue it in a new timestamped file,
+/// without interrupting the rest of the pipeline (and so the RTMP stream).
+///
+/// The split happens at the next keyframe, so the new file is playable on its own.
+fn rotate_recording(file_sink: &gst::Element) {
+    tracing::info!("rotating recording");
+    file_sink.emit_by_name::<()>("split-now", &[]);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RecordingState {
+    Recording,
+    /// Unlinked from the tees, waiting for the file to be closed
+    Stopping,
+    Stopped,
+}
+
+/// The recording output, which can be stopped and started again while
+/// streaming. Stopping unlinks its bin from the tees and sends it EOS, so the
+/// file gets finalized, then takes the bin out of the pipeline. Starting puts
+/// it back and links it to new tee pads, splitmuxsink then opens a new file.
+struct Recording {
+    format: RecordFormat,
+    pipeline: gst::Pipeline,
+    outputs: Arc<Outputs>,
+    file_output: gst::Bin,
+    file_sink: gst::Element,
+    /// Tee feeding each sink pad of `file_output`
+    links: Vec<(gst::Element, &'static str)>,
+    state: Mutex<RecordingState>,
+    /// Tee pads unlinked on stop, released once the file is closed
+    teepads: Mutex<Vec<gst::Pad>>,
+    /// Sink pads of `file_output` yet to get EOS while stopping, the file
+    /// closed before then is only a split
+    eos_pending: Arc<AtomicUsize>,
+}
+
+impl Recording {
+    fn state(&self) -> RecordingState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Start recording again into a new file
+    fn start(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        match *state {
+            RecordingState::Recording => {
+                tracing::debug!("already recording");
+                return Ok(());
+            }
+            RecordingState::Stopping => {
+                tracing::warn!("still finalizing the previous recording, not starting yet");
+                return Ok(());
+            }
+            RecordingState::Stopped => (),
+        }
+
+        self.pipeline
+            .add(&self.file_output)
+            .context("adding the file output")?;
+        for (tee, pad_name) in &self.links {
+            let sinkpad = self
+                .file_output
+                .static_pad(pad_name)
+                .with_context(|| format!("{} without {} pad", self.file_output.name(), pad_name))?;
+
+            tee.request_pad_simple("src_%u")
+                .with_context(|| format!("requesting src pad from {}", tee.name()))?
+                .link(&sinkpad)
+                .with_context(|| {
+                    format!("linking {} to {}", tee.name(), self.file_output.name())
+                })?;
+        }
+        self.file_output
+            .sync_state_with_parent()
+            .context("starting recording")?;
+
+        *state = RecordingState::Recording;
+        tracing::info!("started recording");
+
+        Ok(())
+    }
+
+    /// Unlink the recording from the tees once they're between buffers and
+    /// send it EOS, the rest happens in `finish_stopping` once the file is
+    /// finalized
+    fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if *state != RecordingState::Recording {
+            tracing::debug!(state = ?*state, "not recording");
+            return;
+        }
+
+        // streaming to nowhere would never reach EOS on shutdown
+        if !self.outputs.any_other(&self.file_output) {
+            tracing::warn!("the recording is the only output left, not stopping it");
+            return;
+        }
+
+        *state = RecordingState::Stopping;
+        tracing::info!("stopping recording");
+
+        for sinkpad in self.file_output.sink_pads() {
+            let Some(teepad) = sinkpad.peer() else {
+                continue;
+            };
+            self.teepads.lock().unwrap().push(teepad.clone());
+            self.eos_pending.fetch_add(1, Ordering::Relaxed);
+
+            let eos_pending = self.eos_pending.clone();
+            teepad.add_probe(gst::PadProbeType::IDLE, move |teepad, _| {
+                let _ = teepad.unlink(&sinkpad);
+                sinkpad.send_event(gst::event::Eos::new());
+                eos_pending.fetch_sub(1, Ordering::Relaxed);
+
+                gst::PadProbeReturn::Remove
+            });
+        }
+    }
+
+    /// Stop a running recording, or start a stopped one
+    fn toggle(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.outputs.contains(&self.file_output),
+            "the recording failed earlier, it can't be started again"
+        );
+
+        match self.state() {
+            RecordingState::Stopped => self.start(),
+            _ => {
+                self.stop();
+                Ok(())
+            }
+        }
+    }
+
+    /// Continue the recording in a new file, if recording
+    fn rotate(&self) {
+        if self.state() == RecordingState::Recording {
+            rotate_recording(&self.file_sink);
+        } else {
+            tracing::debug!("not recording, nothing to rotate");
+        }
+    }
+
+    /// Take a stopping recording out of the pipeline once `msg` tells its
+    /// file was finalized
+    fn finish_stopping(&self, msg: &gst::Message) {
+        let mut state = self.state.lock().unwrap();
+
+        let closed = *state == RecordingState::Stopping
+            && self.eos_pending.load(Ordering::Relaxed) == 0
+            && msg.src() == Some(self.file_sink.upcast_ref())
+            && msg
+                .structure()
+                .is_some_and(|s| s.has_name("splitmuxsink-fragment-closed"));
+        if !closed {
+            return;
+        }
+
+        self.detach();
+        *state = RecordingState::Stopped;
+        tracing::info!("stopped recording");
+    }
+
+    /// Release the tee pads feeding the recording and take it out of the pipeline
+    fn detach(&self) {
+        let mut teepads = std::mem::take(&mut *self.teepads.lock().unwrap());
+        teepads.extend(
+            self.file_output
+                .sink_pads()
+                .iter()
+                .filter_map(|sinkpad| sinkpad.peer()),
+        );
+
+        // releasing the tee pads also unlinks them
+        for teepad in teepads {
+            if let Some(tee) = teepad.parent_element() {
+                tee.release_request_pad(&teepad);
+            }
+        }
+
+        let _ = self.file_output.set_state(gst::State::Null);
+        let _ = self.pipeline.remove(&self.file_output);
+    }
+}
+
+/// Push EOS straight into `file_output` and wait up to `timeout` for `file_sink`
+/// to close the current file, for when the pipeline stopped before its own EOS
+/// got there. Without it the file misses its trailer and is cut short or
+/// unplayable.
+fn finalize_recording(
+    bus: &gst::Bus,
+    file_output: &gst::Bin,
+    file_sink: &gst::Element,
+    timeout: Duration,
+) {
+    let mut accepted = false;
+    for pad in file_output.sink_pads() {
+        accepted |= pad.send_event(gst::event::Eos::new());
+    }
+
+    // refused when the branch already got EOS, or was dropped after failing
+    if !accepted {
+        return;
+    }
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let Some(msg) = bus.timed_pop_filtered(
+            gst::ClockTime::from_mseconds(remaining.as_millis() as u64),
+            &[gst::MessageType::Element],
+        ) else {
+            tracing::warn!(
+                ?timeout,
+                "recording not finalized in time, it may be truncated"
+            );
+            return;
+        };
+
+        let closed = msg.src() == Some(file_sink.upcast_ref())
+            && msg
+                .structure()
+                .is_some_and(|s| s.has_name("splitmuxsink-fragment-closed"));
+        if closed {
+            tracing::info!("finalized recording");
+            return;
+        }
+    }
+}
+
+/// Link `elements` one after the other, naming the two that failed and the
+/// `branch` they are part of, where `link_many` would only say linking failed
+fn link_chain(branch: &str, elements: &[impl AsRef<gst::Element>]) -> anyhow::Result<()> {
+    for pair in elements.windows(2) {
+        let (src, sink) = (pair[0].as_ref(), pair[1].as_ref());
+        src.link(sink).with_context(|| {
+            format!(
+                "linking {} to {} in the {}",
+                src.name(),
+                sink.name(),
+                branch
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Link `src` to a newly requested pad of `sink`, for sinks whose request pads
+/// accept any caps and so can't be picked automatically
+fn link_request_pad(src: &gst::Element, sink: &gst::Element, template: &str) -> anyhow::Result<()> {
+    let sinkpad = sink
+        .request_pad_simple(template)
+        .with_context(|| format!("requesting {} pad from {}", template, sink.name()))?;
+
+    src.static_pad("src")
+        .with_context(|| format!("{} without src pad", src.name()))?
+        .link(&sinkpad)
+        .with_context(|| format!("linking {} to {}", src.name(), sink.name()))?;
+
+    Ok(())
+}
+
+/// An output branch in its own bin, fed from tee request pads through the
+/// bin sink pads, so it can be torn down on its own
+struct Output {
+    bin: gst::Bin,
+    /// Set once the output failed, from then on buffers are dropped before
+    /// reaching it
+    failed: Arc<AtomicBool>,
+}
+
+impl Output {
+    fn posted(&self, msg: &gst::Message) -> bool {
+        msg.src().is_some_and(|src| src.has_as_ancestor(&self.bin))
+    }
+}
+
+/// Output branches fed from the same encoded streams, any of which may fail
+/// without stopping the others, e.g. a full disk doesn't end the live stream
+#[derive(Default)]
+struct Outputs {
+    outputs: Mutex<Vec<Output>>,
+}
+
+impl Outputs {
+    /// Add `bin` to `pipeline` as an output, linking each `(tee, pad)` pair
+    /// from a new tee pad to that sink pad of the bin
+    fn add(
+        &self,
+        pipeline: &gst::Pipeline,
+        bin: gst::Bin,
+        links: &[(&gst::Element, &str)],
+    ) -> anyhow::Result<()> {
+        pipeline
+            .add(&bin)
+            .with_context(|| format!("adding the {} output", bin.name()))?;
+
+        let failed = Arc::new(AtomicBool::new(false));
+        for (tee, pad_name) in links {
+            let sinkpad = bin
+                .static_pad(pad_name)
+                .with_context(|| format!("{} without {} pad", bin.name(), pad_name))?;
+
+            sinkpad.add_probe(
+                gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+                {
+                    let failed = failed.clone();
+
+                    move |_, _| {
+                        if failed.load(Ordering::Relaxed) {
+                            gst::PadProbeReturn::Drop
+                        } else {
+                            gst::PadProbeReturn::Ok
+                        }
+                    }
+                },
+            );
+
+            tee.request_pad_simple("src_%u")
+                .with_context(|| format!("requesting src pad from {}", tee.name()))?
+                .link(&sinkpad)
+                .with_context(|| format!("linking {} to {}", tee.name(), bin.name()))?;
+        }
+
+        self.outputs.lock().unwrap().push(Output { bin, failed });
+
+        Ok(())
+    }
+
+    /// Whether `bin` is an output that hasn't failed, and isn't detached from
+    /// the pipeline like a stopped recording
+    fn is_running(&self, bin: &gst::Bin) -> bool {
+        self.outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|o| &o.bin == bin && !o.failed.load(Ordering::Relaxed) && o.bin.parent().is_some())
+    }
+
+    /// Whether any output other than `bin` is in the pipeline
+    fn any_other(&self, bin: &gst::Bin) -> bool {
+        self.outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|o| &o.bin != bin && o.bin.parent().is_some())
+    }
+
+    /// Whether `bin` is still an output, i.e. wasn't removed after failing
+    fn contains(&self, bin: &gst::Bin) -> bool {
+        self.outputs.lock().unwrap().iter().any(|o| &o.bin == bin)
+    }
+
+    /// Stop feeding the output that posted `msg`, if any
+    fn mark_failed(&self, msg: &gst::Message) {
+        let outputs = self.outputs.lock().unwrap();
+
+        if let Some(output) = outputs.iter().find(|o| o.posted(msg)) {
+            output.failed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Remove the output that posted `msg` from `pipeline`, returning false
+    /// when it isn't an output or it was the last one left, as there's nothing
+    /// to keep running for then
+    fn remove_failed(&self, pipeline: &gst::Pipeline, msg: &gst::Message) -> bool {
+        let mut outputs = self.outputs.lock().unwrap();
+
+        let Some(index) = outputs.iter().position(|o| o.posted(msg)) else {
+            return false;
+        };
+        // a stopped recording is out of the pipeline, it doesn't count
+        if outputs.iter().filter(|o| o.bin.parent().is_some()).count() == 1 {
+            return false;
+        }
+
+        let output = outputs.remove(index);
+
+        // releasing the tee pads also unlinks them
+        for sinkpad in output.bin.sink_pads() {
+            if let Some(srcpad) = sinkpad.peer() {
+                if let Some(tee) = srcpad.parent_element() {
+                    tee.release_request_pad(&srcpad);
+                }
+            }
+        }
+
+        let _ = output.bin.set_state(gst::State::Null);
+        let _ = pipeline.remove(&output.bin);
+
+        true
+    }
+}
+
+/// Ingest URL `scheme://server/app/key`, over RTMPS unless `insecure`, Twitch
+/// using `app` as the application path. The server may be given as a bare host
+/// (`live.twitch.tv`) or copied from an ingest URL, with the scheme, the
+/// application path or a trailing slash, which are all dropped. An empty `app`
+/// puts the key right after the server
+fn twitch_url(server: &str, app: &str, key: &str, insecure: bool) -> anyhow::Result<String> {
+    let scheme = if insecure { "rtmp" } else { "rtmps" };
+
+    let host = server.trim();
+    let host = match host.split_once("://") {
+        Some((given, host)) if given == scheme => host,
+        Some(("rtmp", _)) => anyhow::bail!("plain rtmp:// server requires --insecure-rtmp"),
+        Some(("rtmps", _)) => anyhow::bail!("rtmps:// server conflicts with --insecure-rtmp"),
+        Some((given, _)) => anyhow::bail!("unsupported scheme {}:// in server {:?}", given, server),
+        None => host,
+    };
+    let app = app.trim().trim_matches('/');
+    anyhow::ensure!(
+        app.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c)),
+        "application path {:?} contains invalid characters",
+        app
+    );
+
+    let host = host.trim_end_matches('/');
+    let host = match app {
+        "" => host,
+        app => host
+            .strip_suffix(app)
+            .and_then(|host| host.strip_suffix('/'))
+            .unwrap_or(host),
+    };
+
+    validate_host(host).with_context(|| format!("invalid server {:?}", server))?;
+
+    let key = key.trim();
+    anyhow::ensure!(!key.is_empty(), "empty stream key");
+    anyhow::ensure!(
+        !key.contains(|c: char| c == '/' || c.is_whitespace()),
+        "stream key contains a slash or whitespace"
+    );
+
+    match app {
+        "" => Ok(format!("{}://{}/{}", scheme, host, key)),
+        app => Ok(format!("{}://{}/{}/{}", scheme, host, app, key)),
+    }
+}
+
+/// Check `host` is a host name or address with an optional port
+fn validate_host(host: &str) -> anyhow::Result<()> {
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) => (name, Some(port)),
+        None => (host, None),
+    };
+
+    anyhow::ensure!(!name.is_empty(), "missing host");
+    anyhow::ensure!(
+        name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'),
+        "host {:?} contains invalid characters",
+        name
+    );
+    if let Some(port) = port {
+        port.parse::<u16>()
+            .with_context(|| format!("invalid port {:?}", port))?;
+    }
+
+    Ok(())
+}
+
+/// Check a full `rtmp[s]://host/app/key` URL given on the command line
+fn parse_rtmp_url(value: &str) -> Result<String, String> {
+    let (scheme, rest) = value
+        .split_once("://")
+        .ok_or("expected rtmp://host/app/key or rtmps://host/app/key")?;
+    if !matches!(scheme, "rtmp" | "rtmps") {
+        return Err(format!("unsupported scheme {}://", scheme));
+    }
+
+    let (host, path) = rest.split_once('/').ok_or("missing application path")?;
+    validate_host(host).map_err(|err| err.to_string())?;
+    if path.trim_matches('/').is_empty() {
+        return Err("missing application path".into());
+    }
+
+    Ok(value.into())
+}
+
+/// Bin streaming FLV to a single RTMP `location`, through its "sink" pad
+fn make_rtmp_destination(
+    index: usize,
+    location: &str,
+    low_latency: bool,
+) -> anyhow::Result<gst::Bin> {
+    let bin = gst::Bin::builder()
+        .name(format!("rtmp-destination-{}", index))
+        .build();
+
+    let queue = make_output_queue(low_latency)?;
+    let sink = make_element("rtmp2sink")?
+        .property_from_str("location", location)
+        .build()?;
+
+    bin.add_many(&[&queue, &sink])
+        .context("adding the RTMP destination")?;
+    link_chain("RTMP destination", &[&queue, &sink])?;
+    add_ghost_sink_pad(&bin, &queue, "sink")?;
+
+    Ok(bin)
+}
+
+/// Expose the sink pad of `element`, inside `bin`, as the bin `name` pad
+fn add_ghost_sink_pad(bin: &gst::Bin, element: &gst::Element, name: &str) -> anyhow::Result<()> {
+    let target = element
+        .static_pad("sink")
+        .with_context(|| format!("{} without sink pad", element.name()))?;
+
+    let pad = gst::GhostPad::builder_with_target(&target)?
+        .name(name)
+        .build();
+    bin.add_pad(&pad)?;
+
+    Ok(())
+}
+
+/// Messages sent by the receiver to the producer, through the producer stdin
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ControlMessage {
+    /// Stop capturing and exit
+    Stop,
+    /// Sent periodically so the producer knows the link is alive
+    Heartbeat,
+}
+
+impl ControlMessage {
+    /// Each message is a single byte on the wire, `Stop` keeps the `\n` used
+    /// before messages were typed
+    fn to_byte(self) -> u8 {
+        match self {
+            ControlMessage::Stop => 0xa,
+            ControlMessage::Heartbeat => b'h',
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0xa => Some(ControlMessage::Stop),
+            b'h' => Some(ControlMessage::Heartbeat),
+            _ => None,
+        }
+    }
+
+    fn write_to(self, dest: &mut impl Write) -> std::io::Result<()> {
+        dest.write_all(&[self.to_byte()])?;
+        dest.flush()
+    }
+
+    /// Block until the next message arrives on `src`, skipping any byte that
+    /// isn't a known message. Returns `None` once `src` is closed.
+    fn read_from(src: &mut impl Read) -> std::io::Result<Option<Self>> {
+        let mut byte = [0u8; 1];
+
+        loop {
+            match src.read(&mut byte) {
+                // a zero-length read means the writer closed its end
+                Ok(0) => return Ok(None),
+                Ok(_) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+
+            match ControlMessage::from_byte(byte[0]) {
+                Some(message) => return Ok(Some(message)),
+                None => tracing::warn!(byte = byte[0], "ignoring unknown control message"),
+            }
+        }
+    }
+}
+
+/// Send a control message to the producer on the other end of stdout
+fn send_control(message: ControlMessage) {
+    if let Err(err) = message.write_to(&mut std::io::stdout()) {
+        tracing::error!(?err, ?message, "failed to send control message to producer");
+    }
+}
+
+/// Receiver configuration resolved from the command line and the producer
+/// header, building the pipeline one branch at a time
+pub struct PipelineBuilder<'a> {
+    args: &'a ReceiveArgs,
+    video_info: VideoInfo,
+    queue: QueueArgs,
+    settings: EncodingSettings,
+    input_fd: RawFd,
+    silent_audio: bool,
+    pipeline: gst::Pipeline,
+}
+
+/// Built receiver pipeline, along with the elements driven while it runs
+pub struct ReceiverPipeline {
+    pub pipeline: gst::Pipeline,
+    video: VideoBranch,
+    audio: AudioBranch,
+    outputs: OutputBranches,
+}
+
+/// Raw video from the producer up to the encoded video tee
+struct VideoBranch {
+    videosrc: gst::Element,
+    raw_input: Arc<RawInput>,
+    rawvideoparsequeue: gst::Element,
+    rawvideoparse: gst::Element,
+    videoqueue: gst::Element,
+    encoder: Encoder,
+    videoenc: gst::Element,
+    /// Bounds of the adaptive bitrate, in kbit/s
+    adaptive_bitrate: Option<(u32, u32)>,
+    /// Resolution and framerate of the encoded video
+    resolution: Resolution,
+    framerate: gst::Fraction,
+    tee: gst::Element,
+    /// Raw video ahead of the encoder, for a recording encoding its own
+    raw_tee: Option<gst::Element>,
+}
+
+/// Audio capture up to the encoded audio tee
+struct AudioBranch {
+    tee: gst::Element,
+    /// Raw audio ahead of the encoder, for a recording encoding its own
+    raw_tee: Option<gst::Element>,
+}
+
+/// Everything fed from the encoded video and audio tees
+struct OutputBranches {
+    outputs: Arc<Outputs>,
+    recording: Arc<Recording>,
+    rtmp: Option<RtmpBranch>,
+}
+
+/// FLV muxed once for every RTMP destination
+struct RtmpBranch {
+    videoqueue: gst::Element,
+    tee: gst::Element,
+    destinations: Vec<gst::Bin>,
+    /// Ingest URL of each destination, stream key included
+    locations: Vec<String>,
+}
+
+impl<'a> PipelineBuilder<'a> {
+    pub fn new(args: &'a ReceiveArgs, video_info: VideoInfo) -> Self {
+        let queue = if args.low_latency {
+            args.queue.with_low_latency_defaults()
+        } else {
+            args.queue
+        };
+
+        let settings = args.encoding_settings();
+        if let Some(preset) = args.preset {
+            tracing::info!(?preset, ?settings, "using encoding preset");
+        }
+
+        PipelineBuilder {
+            args,
+            video_info,
+            queue,
+            settings,
+            input_fd: 0,
+            silent_audio: false,
+            pipeline: gst::Pipeline::new(),
+        }
+    }
+
+    /// Read the raw video from `fd` instead of stdin
+    pub fn input_fd(mut self, fd: RawFd) -> Self {
+        self.input_fd = fd;
+        self
+    }
+
+    /// Record silence instead of capturing PulseAudio
+    pub fn silent_audio(mut self, silent: bool) -> Self {
+        self.silent_audio = silent;
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<ReceiverPipeline> {
+        if self.args.audio_sync_to_video {
+            // a replay is timestamped from its framerate, not by any clock
+            anyhow::ensure!(
+                self.args.live(),
+                "--audio-sync-to-video needs live raw video, add --live to replay a file"
+            );
+
+            // the system clock the producer paces its capture by, rather than
+            // the sound card's pulsesrc would provide
+            self.pipeline.use_clock(Some(&gst::SystemClock::obtain()));
+            tracing::info!("slaving the audio capture to the system clock");
+        }
+
+        let audio = self.build_audio_branch()?;
+        let video = self.build_video_branch()?;
+        let outputs = self.build_outputs(&video, &audio)?;
+
+        self.log_summary(&video, &outputs);
+
+        Ok(ReceiverPipeline {
+            pipeline: self.pipeline,
+            video,
+            audio,
+            outputs,
+        })
+    }
+
+    /// One line with the negotiated input and the effective encoding and
+    /// outputs, to check what a stream actually runs with
+    fn log_summary(&self, video: &VideoBranch, outputs: &OutputBranches) {
+        let args = self.args;
+        let video_info = &self.video_info;
+
+        let input = Resolution {
+            width: video_info.width,
+            height: video_info.height,
+        };
+        let recording = if args.defer_recording {
+            "deferred".to_string()
+        } else {
+            format!("{:?}", outputs.recording.format)
+        };
+        let rtmp = outputs
+            .rtmp
+            .as_ref()
+            .map(|rtmp| redact_rtmp_urls(&rtmp.locations.join(" ")))
+            .unwrap_or_default();
+
+        tracing::info!(
+            %input,
+            input_framerate = %video_info.framerate,
+            format = %video_info.format,
+            colorimetry = %video_info.colorimetry,
+            live = args.live(),
+            output = %video.resolution,
+            framerate = %video.framerate,
+            encoder = video.encoder.factory_name(),
+            bitrate_kbps = video.encoder.bitrate_kbps(&video.videoenc),
+            rate_control = ?self.settings.rate_control,
+            adaptive_bitrate = ?video.adaptive_bitrate,
+            %recording,
+            %rtmp,
+            hls = ?args.hls_dir,
+            "receiver configuration"
+        );
+    }
+
+    /// Container of the local recording, with the codec defaults applied
+    fn record_format(&self) -> RecordFormat {
+        self.args.record_format.unwrap_or(match self.args.codec {
+            Codec::H264 => RecordFormat::Flv,
+            Codec::H265 => RecordFormat::Mkv,
+        })
+    }
+
+    /// Tee splitting the raw video or audio ahead of the encoder, when the
+    /// recording encodes its own
+    fn make_raw_tee(&self) -> anyhow::Result<Option<gst::Element>> {
+        if !self.record_format().reencodes() {
+            return Ok(None);
+        }
+
+        let tee = make_element("tee")?
+            .property("allow-not-linked", true)
+            .build()?;
+
+        Ok(Some(tee))
+    }
+
+    /// Factory names of every element the pipeline is built from with these
+    /// options, following the branch builders
+    fn required_elements(&self) -> Vec<&'static str> {
+        let args = self.args;
+        let record_format = self.record_format();
+        let mut elements = vec!["fdsrc", "queue", "capsfilter", "tee"];
+        if args.av_offset != 0 {
+            elements.push("identity");
+        }
+
+        // audio
+        if args.test_source || args.silent_audio_fallback {
+            elements.push("audiotestsrc");
+        }
+        if !args.test_source {
+            elements.push("pulsesrc");
+        }
+        elements.extend(["audioconvert", "audioresample", "fdkaacenc"]);
+        if !args.no_audio_filters {
+            elements.extend(["audiocheblimit", "equalizer-10bands"]);
+        }
+        if args.audio_sync_to_video {
+            elements.push("audiorate");
+        }
+
+        // video
+        elements.extend([
+            "rawvideoparse",
+            "videorate",
+            "videoconvert",
+            Encoder::select(args.codec).factory_name(),
+        ]);
+        if self.settings.output_resolution.is_some() {
+            elements.push("videoscale");
+        }
+        if self.settings.h264_profile.is_some() || args.h264_level.is_some() {
+            elements.push(Codec::H264.parser_name());
+        }
+        if args.overlay_text.is_some() {
+            elements.push("textoverlay");
+        }
+        if args.overlay_clock {
+            elements.push("clockoverlay");
+        }
+
+        // outputs
+        if record_format.reencodes() {
+            elements.extend(["vp9enc", "opusenc"]);
+        } else {
+            elements.push(args.codec.parser_name());
+        }
+        elements.extend([record_format.muxer_name(), "splitmuxsink"]);
+        if !args.local_only && args.codec == Codec::H264 {
+            elements.extend([args.codec.parser_name(), "flvmux", "identity", "rtmp2sink"]);
+        }
+        if args.hls_dir.is_some() {
+            elements.extend([args.codec.parser_name(), "hlssink2"]);
+        }
+
+        elements.sort_unstable();
+        elements.dedup();
+        elements
+    }
+
+    /// Low-pass filter and equalizer, in the order they're linked
+    fn make_audio_filters(&self) -> anyhow::Result<Vec<gst::Element>> {
+        let args = self.args;
+
+        let nyquist = args.audio_rate as f32 / 2.0;
+        anyhow::ensure!(
+            args.audio_lowpass_cutoff > 0.0 && args.audio_lowpass_cutoff <= nyquist,
+            "low-pass cutoff {}Hz must be between 0 and {}Hz (half the audio rate)",
+            args.audio_lowpass_cutoff,
+            nyquist
+        );
+
+        let audio_lowpassfilter = make_element("audiocheblimit")?
+            .property("cutoff", args.audio_lowpass_cutoff)
+            .property("poles", args.audio_lowpass_poles)
+            .build()?;
+
+        let audioconvert_afterfilter = make_element("audioconvert")?.build()?;
+
+        let audioequalizer = make_element("equalizer-10bands")?.build()?;
+        if !args.eq_bands.is_empty() {
+            anyhow::ensure!(
+                args.eq_bands.len() == 10,
+                "expected 10 equalizer band gains, got {}",
+                args.eq_bands.len()
+            );
+
+            for (band, &gain) in args.eq_bands.iter().enumerate() {
+                let clamped = gain.clamp(EQ_MIN_GAIN, EQ_MAX_GAIN);
+                if clamped != gain {
+                    tracing::warn!(band, gain, clamped, "equalizer gain out of range, clamping");
+                }
+
+                audioequalizer.set_property(&format!("band{}", band), clamped);
+            }
+        }
+
+        Ok(vec![
+            audio_lowpassfilter,
+            audioconvert_afterfilter,
+            audioequalizer,
+        ])
+    }
+
+    /// Add the audio capture and encoding to the pipeline
+    fn build_audio_branch(&self) -> anyhow::Result<AudioBranch> {
+        let args = self.args;
+
+        let audiosrc = if args.test_source {
+            make_element("audiotestsrc")?
+                .property("is-live", true)
+                .build()?
+        } else if self.silent_audio {
+            make_element("audiotestsrc")?
+                .property("is-live", true)
+                .property_from_str("wave", "silence")
+                .build()?
+        } else {
+            make_element("pulsesrc")?.build()?
+        };
+        let audioconvert = make_element("audioconvert")?.build()?;
+        let audioresample = make_element("audioresample")?.build()?;
+        anyhow::ensure!(
+            AUDIO_RATES.contains(&args.audio_rate),
+            "unsupported audio rate {}, expected one of {:?}",
+            args.audio_rate,
+            AUDIO_RATES
+        );
+
+        let caps = gst::Caps::builder("audio/x-raw")
+            .field("rate", args.audio_rate)
+            .field("channels", args.audio_channels)
+            .build();
+
+        let resampleconfig = make_element("capsfilter")?
+            .property("caps", &caps)
+            .build()?;
+
+        let audiocompress = make_element("fdkaacenc")?
+            .property("bitrate", 160000i32)
+            .build()?;
+
+        let audioqueue = make_element("queue")?.build()?;
+
+        // only ever delay one side, shifting timestamps backwards could make them negative
+        let audio_delay = make_delay(args.av_offset)?;
+        if args.av_offset != 0 {
+            tracing::info!(
+                av_offset_ms = args.av_offset,
+                "shifting audio against video"
+            );
+        }
+
+        // encoded streams are split here, so each output muxes into its own container.
+        // Failed outputs get unlinked, which the tee must tolerate
+        let audiotee = make_element("tee")?
+            .property("allow-not-linked", true)
+            .build()?;
+
+        // slaved to the pipeline clock, the capture skips or repeats samples to
+        // follow it, audiorate then fills the gaps so the audio stays continuous
+        let audiorate = if args.audio_sync_to_video {
+            if audiosrc.find_property("provide-clock").is_some() {
+                audiosrc.set_property("provide-clock", false);
+                audiosrc.set_property_from_str("slave-method", "skew");
+            }
+            Some(
+                make_element("audiorate")?
+                    .property("skip-to-first", true)
+                    .build()?,
+            )
+        } else {
+            None
+        };
+
+        let mut audio_chain = vec![&audiosrc];
+        audio_chain.extend(&audiorate);
+        audio_chain.push(&audioconvert);
+        let audio_filters = if args.no_audio_filters {
+            Vec::new()
+        } else {
+            self.make_audio_filters()?
+        };
+        audio_chain.extend(&audio_filters);
+        let raw_tee = self.make_raw_tee()?;
+
+        audio_chain.extend([&audioresample, &resampleconfig]);
+        audio_chain.extend(&raw_tee);
+        audio_chain.extend([&audioqueue, &audiocompress]);
+        audio_chain.extend(&audio_delay);
+        audio_chain.push(&audiotee);
+
+        self.pipeline
+            .add_many(&audio_chain)
+            .context("adding the audio branch")?;
+        link_chain("audio branch", &audio_chain)?;
+
+        Ok(AudioBranch {
+            tee: audiotee,
+            raw_tee,
+        })
+    }
+
+    /// Add the raw video parsing, conversion and encoding to the pipeline
+    fn build_video_branch(&self) -> anyhow::Result<VideoBranch> {
+        let args = self.args;
+        let video_info = &self.video_info;
+        let settings = &self.settings;
+
+        let input_colorimetry = args
+            .input_colorimetry
+            .as_deref()
+            .unwrap_or(&video_info.colorimetry);
+
+        // nothing can convert the bytes before they're parsed into frames
+        check_raw_format(&video_info.format)?;
+
+        let videosrc = make_element("fdsrc")?
+            .property("fd", self.input_fd)
+            .property("is-live", args.live())
+            .property("do-timestamp", args.live())
+            .build()?;
+
+        let stdin_videoconfig = make_element("capsfilter")?
+            .property("caps", raw_video_caps(video_info, input_colorimetry))
+            .build()?;
+
+        let rawvideoparse = make_element("rawvideoparse")?.build()?;
+        configure_rawvideoparse(&rawvideoparse, video_info, input_colorimetry)?;
+
+        // the element defaults are the fast path, quality is opt-in
+        let videoconvert = make_element("videoconvert")?.build()?;
+        if let Some(dither) = args.convert_dither {
+            videoconvert.set_property_from_str("dither", dither.as_str());
+        }
+        if let Some(resampler) = args.convert_chroma_resampler {
+            videoconvert.set_property_from_str("chroma-resampler", resampler.as_str());
+        }
+        if let Some(resampler) = args.convert_method {
+            videoconvert.set_property_from_str("method", resampler.as_str());
+        }
+
+        let stdin_videoconfig2 = make_element("capsfilter")?
+            .property("caps", raw_video_caps(video_info, input_colorimetry))
+            .build()?;
+
+        let framerate = settings.framerate.unwrap_or(video_info.framerate);
+        if framerate != video_info.framerate {
+            tracing::info!(input = %video_info.framerate, output = %framerate, "converting framerate");
+        }
+
+        let videorate = make_element("videorate")?.build()?;
+
+        let videorateconfig = make_element("capsfilter")?
+            .property(
+                "caps",
+                gst::Caps::builder("video/x-raw")
+                    .field("framerate", framerate)
+                    .build(),
+            )
+            .build()?;
+
+        let encoder = Encoder::select(args.codec);
+
+        // the conversion and the encoder tagging both follow the output
+        // colorimetry, whatever the encoder
+        let colorimetry = args.colorimetry.caps_string(args.color_range);
+        tracing::debug!(input = input_colorimetry, output = %colorimetry, "converting colorimetry");
+
+        let videoconvertconfig = make_element("capsfilter")?
+            .property("caps", encoder.input_caps(&colorimetry))
+            .build()?;
+
+        let videoenc = encoder.make()?;
+
+        // before the rate control, which sizes its buffers after the bitrate
+        if let Some(bitrate) = settings.bitrate {
+            encoder.set_bitrate_kbps(&videoenc, bitrate);
+        }
+
+        if let Some(rate_control) = settings.rate_control {
+            encoder.set_rate_control(&videoenc, rate_control);
+        }
+
+        if args.low_latency {
+            encoder.set_low_latency(&videoenc);
+        }
+
+        encoder.set_latency_options(
+            &videoenc,
+            args.bframes,
+            args.rc_lookahead,
+            args.zerolatency,
+        )?;
+
+        if let Some(preset) = &args.encoder_preset {
+            let property = encoder
+                .preset_property()
+                .with_context(|| format!("{} has no preset", encoder.factory_name()))?;
+            set_enum_property(&videoenc, property, preset)?;
+        }
+
+        if let Some(tune) = &args.encoder_tune {
+            let property = encoder
+                .tune_property()
+                .with_context(|| format!("{} has no tune", encoder.factory_name()))?;
+            set_enum_property(&videoenc, property, tune)?;
+        }
+
+        if encoder == Encoder::OpenH264 {
+            if let Some(complexity) = args.sw_complexity {
+                videoenc.set_property_from_str("complexity", complexity.as_str());
+            }
+            if let Some(usage_type) = args.sw_usage_type {
+                videoenc.set_property_from_str("usage-type", usage_type.as_str());
+            }
+        } else if args.sw_complexity.is_some() || args.sw_usage_type.is_some() {
+            tracing::warn!(
+                ?encoder,
+                "not using the software H.264 encoder, ignoring --sw-* options"
+            );
+        }
+
+        let adaptive_bitrate = if args.adaptive_bitrate {
+            let max_kbps = args
+                .max_bitrate
+                .unwrap_or_else(|| encoder.bitrate_kbps(&videoenc));
+            anyhow::ensure!(
+                args.min_bitrate <= max_kbps,
+                "--min-bitrate {} is above the maximum bitrate {}",
+                args.min_bitrate,
+                max_kbps
+            );
+
+            Some((args.min_bitrate, max_kbps))
+        } else {
+            None
+        };
+
+        let rawvideoparsequeue = self.queue.make_queue()?;
+
+        // frequent keyframes let viewers start decoding sooner
+        let keyframe_interval = if args.low_latency {
+            settings.keyframe_interval.or(Some(1))
+        } else {
+            settings.keyframe_interval
+        };
+
+        if let Some(keyframe_interval) = keyframe_interval {
+            let gop_size = gop_size(keyframe_interval, framerate);
+            tracing::debug!(keyframe_interval, gop_size, "setting keyframe interval");
+
+            // the property types differ between encoders, parsing from a string handles all of them
+            let property = match encoder {
+                Encoder::X265 => "key-int-max",
+                _ => "gop-size",
+            };
+            videoenc.set_property_from_str(property, &gop_size.to_string());
+        }
+
+        let input = Resolution {
+            width: video_info.width,
+            height: video_info.height,
+        };
+        let output = settings.output_resolution.map(|resolution| {
+            if args.letterbox {
+                resolution
+            } else {
+                resolution.fit(input.width, input.height)
+            }
+        });
+
+        // scaling after the conversion works on the smaller subsampled frames
+        let output_scaling = match output {
+            Some(output) => {
+                tracing::info!(%input, %output, letterbox = args.letterbox, "scaling video");
+
+                let videoscale = make_element("videoscale")?
+                    .property("add-borders", args.letterbox)
+                    .build()?;
+
+                let scaleconfig = make_element("capsfilter")?
+                    .property("caps", scaled_caps(output))
+                    .build()?;
+
+                vec![videoscale, scaleconfig]
+            }
+            None => vec![],
+        };
+
+        // h264parse in front of the capsfilter lets the constraints negotiate with the encoder
+        let h264_constraints = if settings.h264_profile.is_some() || args.h264_level.is_some() {
+            anyhow::ensure!(
+                args.codec == Codec::H264,
+                "--h264-profile and --h264-level only apply to --codec h264"
+            );
+
+            let h264caps = gst::Caps::builder("video/x-h264")
+                .field_if_some("profile", settings.h264_profile.map(H264Profile::as_str))
+                .field_if_some("level", args.h264_level.map(H264Level::as_str))
+                .build();
+
+            let encoder_caps = videoenc
+                .static_pad("src")
+                .context("encoder without src pad")?
+                .pad_template_caps();
+            anyhow::ensure!(
+                encoder_caps.can_intersect(&h264caps),
+                "{} does not support {}",
+                videoenc.name(),
+                h264caps
+            );
+
+            // the level is only a label in the stream, nothing holds the
+            // encoder to it, so a mismatch is left to the user to fix
+            if let Some(level) = args.h264_level {
+                let kbps = match adaptive_bitrate {
+                    Some((_, max_kbps)) => max_kbps,
+                    None => encoder.bitrate_kbps(&videoenc),
+                };
+
+                for violation in h264_level_violations(
+                    level,
+                    settings.h264_profile,
+                    output.unwrap_or(input),
+                    framerate,
+                    kbps,
+                ) {
+                    tracing::warn!(
+                        "{}, players limited to level {} may fail to decode the stream",
+                        violation,
+                        level.as_str()
+                    );
+                }
+            }
+
+            vec![
+                make_element("h264parse")?.build()?,
+                make_element("capsfilter")?
+                    .property("caps", &h264caps)
+                    .build()?,
+            ]
+        } else {
+            Vec::new()
+        };
+
+        // drawn at the output resolution, so the font size holds whatever the producer sends
+        let mut overlays = Vec::new();
+        if let Some(text) = &args.overlay_text {
+            let overlay = make_overlay(
+                "textoverlay",
+                args.overlay_text_position,
+                args.overlay_font_size,
+            )?;
+            overlay.set_property("text", text.as_str());
+            overlays.push(overlay);
+        }
+        if args.overlay_clock {
+            let overlay = make_overlay(
+                "clockoverlay",
+                args.overlay_clock_position,
+                args.overlay_font_size,
+            )?;
+            overlay.set_property("time-format", "%Y-%m-%d %H:%M:%S");
+            overlays.push(overlay);
+        }
+
+        let videoqueue = self.queue.make_queue()?;
+
+        // the audio branch delays positive offsets, video takes the negative ones
+        let video_delay = make_delay(args.av_offset.saturating_neg())?;
+
+        // like the audio tee, each output muxes its own copy of the encoded video
+        let videotee = make_element("tee")?
+            .property("allow-not-linked", true)
+            .build()?;
+
+        let mut video_chain = vec![
+            &videosrc,
+            &rawvideoparsequeue,
+            &stdin_videoconfig,
+            &rawvideoparse,
+            &stdin_videoconfig2,
+            &videorate,
+            &videorateconfig,
+            &videoconvert,
+            &videoconvertconfig,
+        ];
+        let raw_tee = self.make_raw_tee()?;
+
+        video_chain.extend(&output_scaling);
+        video_chain.extend(&overlays);
+        video_chain.extend(&raw_tee);
+        video_chain.extend([&videoqueue, &videoenc]);
+        video_chain.extend(&h264_constraints);
+        video_chain.extend(&video_delay);
+        video_chain.push(&videotee);
+
+        self.pipeline
+            .add_many(&video_chain)
+            .context("adding the video branch")?;
+        link_chain("video branch", &video_chain)?;
+
+        let raw_input = Arc::new(RawInput {
+            capsfilters: [stdin_videoconfig.clone(), stdin_videoconfig2.clone()],
+            rawvideoparse: rawvideoparse.clone(),
+            scaling: output_scaling.get(1).map(|scaleconfig| {
+                let fit_within = settings.output_resolution.filter(|_| !args.letterbox);
+                (scaleconfig.clone(), fit_within)
+            }),
+            input_colorimetry: args.input_colorimetry.clone(),
+            splitter: Mutex::new(NonZeroU32::new(video_info.frame_size).map(HeaderSplitter::new)),
+        });
+        if raw_input.splitter.lock().unwrap().is_some() {
+            raw_input.follow_headers(
+                &stdin_videoconfig
+                    .static_pad("sink")
+                    .context("capsfilter without sink pad")?,
+            );
+        }
+
+        Ok(VideoBranch {
+            videosrc,
+            raw_input,
+            rawvideoparsequeue,
+            rawvideoparse,
+            videoqueue,
+            encoder,
+            videoenc,
+            adaptive_bitrate,
+            resolution: output.unwrap_or(input),
+            framerate,
+            tee: videotee,
+            raw_tee,
+        })
+    }
+
+    /// Add the recording, RTMP and HLS outputs fed from the encoded tees
+    fn build_outputs(
+        &self,
+        video: &VideoBranch,
+        audio: &AudioBranch,
+    ) -> anyhow::Result<OutputBranches> {
+        let args = self.args;
+        let pipeline = &self.pipeline;
+        let videotee = &video.tee;
+        let audiotee = &audio.tee;
+
+        let record_format = self.record_format();
+        anyhow::ensure!(
+            !(args.codec == Codec::H265 && record_format == RecordFormat::Flv),
+            "FLV can't hold HEVC, use --record-format mp4 or mkv"
+        );
+        anyhow::ensure!(
+            !args.record_flv_streamable || record_format == RecordFormat::Flv,
+            "--record-flv-streamable needs --record-format flv"
+        );
+
+        // flvmux has no HEVC support, so H.265 is only recorded locally
+        let stream_to_rtmp = !args.local_only && args.codec == Codec::H264;
+        if !args.local_only && !stream_to_rtmp {
+            tracing::warn!(
+                codec = ?args.codec,
+                "Twitch RTMP ingest does not accept HEVC, recording to a local file only"
+            );
+        }
+
+        let outputs = Arc::new(Outputs::default());
+
+        let (file_output, file_sink) = self.build_file_output(record_format)?;
+
+        if record_format.reencodes() {
+            tracing::warn!(
+                ?record_format,
+                "encoding the recording separately, expect a high CPU load"
+            );
+        }
+
+        let file_videotee = video.raw_tee.as_ref().unwrap_or(videotee);
+        let file_audiotee = audio.raw_tee.as_ref().unwrap_or(audiotee);
+
+        outputs.add(
+            pipeline,
+            file_output.clone(),
+            &[(file_videotee, "video"), (file_audiotee, "audio")],
+        )?;
+
+        let recording = Arc::new(Recording {
+            format: record_format,
+            pipeline: pipeline.clone(),
+            outputs: outputs.clone(),
+            file_output,
+            file_sink,
+            links: vec![
+                (file_videotee.clone(), "video"),
+                (file_audiotee.clone(), "audio"),
+            ],
+            state: Mutex::new(RecordingState::Recording),
+            teepads: Default::default(),
+            eos_pending: Default::default(),
+        });
+
+        if args.defer_recording {
+            // nothing flows yet, it can be taken out right away
+            recording.detach();
+            *recording.state.lock().unwrap() = RecordingState::Stopped;
+            tracing::info!("not recording until SIGHUP");
+        }
+
+        let twitch_key = read_twitch_key(args)?;
+        let rtmp = if stream_to_rtmp {
+            Some(self.build_rtmp_branch(&outputs, videotee, audiotee, twitch_key)?)
+        } else {
+            None
+        };
+
+        if let Some(hls_dir) = &args.hls_dir {
+            std::fs::create_dir_all(hls_dir)
+                .with_context(|| format!("creating HLS directory {}", hls_dir.display()))?;
+
+            let hls_videoqueue = make_element("queue")?.build()?;
+            let hls_videoparse = args.codec.make_parser()?;
+            let hls_audioqueue = make_element("queue")?.build()?;
+
+            let hls_sink = make_element("hlssink2")?
+                .property(
+                    "location",
+                    hls_dir
+                        .join("segment%05d.ts")
+                        .to_str()
+                        .context("HLS directory is not valid UTF-8")?,
+                )
+                .property(
+                    "playlist-location",
+                    hls_dir
+                        .join("playlist.m3u8")
+                        .to_str()
+                        .context("HLS directory is not valid UTF-8")?,
+                )
+                .property("target-duration", args.hls_segment_duration)
+                .property("playlist-length", args.hls_max_segments)
+                .property("max-files", args.hls_max_segments)
+                .build()?;
+
+            let hls_output = gst::Bin::builder().name("hls-output").build();
+            hls_output
+                .add_many(&[&hls_videoqueue, &hls_videoparse, &hls_audioqueue, &hls_sink])
+                .context("adding the HLS output")?;
+            link_chain("HLS output", &[&hls_videoqueue, &hls_videoparse])?;
+            link_request_pad(&hls_videoparse, &hls_sink, "video")?;
+            link_request_pad(&hls_audioqueue, &hls_sink, "audio")?;
+            add_ghost_sink_pad(&hls_output, &hls_videoqueue, "video")?;
+            add_ghost_sink_pad(&hls_output, &hls_audioqueue, "audio")?;
+
+            outputs.add(
+                pipeline,
+                hls_output,
+                &[(videotee, "video"), (audiotee, "audio")],
+            )?;
+        }
+
+        Ok(OutputBranches {
+            outputs,
+            recording,
+            rtmp,
+        })
+    }
+
+    /// Bin recording the video and audio fed through its "video" and "audio"
+    /// pads, along with its splitmuxsink
+    fn build_file_output(
+        &self,
+        record_format: RecordFormat,
+    ) -> anyhow::Result<(gst::Bin, gst::Element)> {
+        let args = self.args;
+
+        let mut video_chain = vec![make_element("queue")?.build()?];
+        let mut audio_chain = vec![make_element("queue")?.build()?];
+
+        if record_format.reencodes() {
+            let kbps = self.settings.bitrate.unwrap_or(6000);
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+            // the stream shifts its encoded audio and video, not the raw ones
+            video_chain.extend(make_delay(args.av_offset.saturating_neg())?);
+            video_chain.push(
+                make_element("vp9enc")?
+                    .property("deadline", 1i64)
+                    .property("cpu-used", 8i32)
+                    .property("threads", i32::try_from(threads).unwrap_or(1))
+                    .property(
+                        "target-bitrate",
+                        i32::try_from(kbps.saturating_mul(1000))
+                            .context("--bitrate out of range")?,
+                    )
+                    .build()?,
+            );
+
+            audio_chain.extend(make_delay(args.av_offset)?);
+            audio_chain.extend([
+                make_element("audioconvert")?.build()?,
+                // Opus doesn't take every rate AAC does
+                make_element("audioresample")?.build()?,
+                make_element("opusenc")?.build()?,
+            ]);
+        } else {
+            video_chain.push(args.codec.make_parser()?);
+        }
+
+        let muxer = record_format.make_muxer()?;
+        if args.record_flv_streamable {
+            muxer.set_property("streamable", true);
+        }
+        if let Some(ms) = args.record_mux_latency {
+            set_mux_latency(&muxer, ms)?;
+        }
+
+        // splitmuxsink finalizes each file properly on EOS and can start a new one on demand
+        let file_sink = make_element("splitmuxsink")?
+            .property("muxer", muxer)
+            .build()?;
+
+        // limits are checked on every keyframe, so each file starts with one
+        if let Some(secs) = args.record_max_duration {
+            file_sink.set_property(
+                "max-size-time",
+                gst::ClockTime::from_seconds(secs).nseconds(),
+            );
+        }
+        if let Some(mb) = args.record_max_size {
+            file_sink.set_property("max-size-bytes", mb.saturating_mul(1_000_000));
+        }
+
+        let file_extension = record_format.extension();
+        file_sink.connect("format-location", false, move |_| {
+            Some(recording_file_name(file_extension).to_value())
+        });
+
+        let file_output = gst::Bin::builder().name("file-output").build();
+        file_output
+            .add_many(video_chain.iter().chain(&audio_chain))
+            .context("adding the file output")?;
+        file_output
+            .add(&file_sink)
+            .context("adding the file sink")?;
+
+        link_chain("file output video chain", &video_chain)?;
+        link_chain("file output audio chain", &audio_chain)?;
+        link_request_pad(video_chain.last().unwrap(), &file_sink, "video")?;
+        link_request_pad(audio_chain.last().unwrap(), &file_sink, "audio_%u")?;
+        add_ghost_sink_pad(&file_output, &video_chain[0], "video")?;
+        add_ghost_sink_pad(&file_output, &audio_chain[0], "audio")?;
+
+        Ok((file_output, file_sink))
+    }
+
+    fn build_rtmp_branch(
+        &self,
+        outputs: &Outputs,
+        videotee: &gst::Element,
+        audiotee: &gst::Element,
+        twitch_key: Option<String>,
+    ) -> anyhow::Result<RtmpBranch> {
+        let args = self.args;
+
+        let rtmp_locations = args
+            .twitch_server
+            .iter()
+            .zip(&twitch_key)
+            .map(|(server, key)| twitch_url(server, &args.rtmp_app, key, args.insecure_rtmp))
+            .chain(args.rtmp_urls.iter().cloned().map(Ok))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if rtmp_locations
+            .iter()
+            .any(|location| location.starts_with("rtmp://"))
+        {
+            tracing::warn!(
+                "streaming over unencrypted RTMP, the stream key and video can be read \
+                 and tampered with on the network"
+            );
+        }
+
+        let destinations = rtmp_locations
+            .iter()
+            .enumerate()
+            .map(|(index, location)| make_rtmp_destination(index, location, args.low_latency))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let rtmp_videoqueue = make_output_queue(args.low_latency)?;
+        let rtmp_videoparse = args.codec.make_parser()?;
+        let rtmp_audioqueue = make_output_queue(args.low_latency)?;
+
+        // Twitch requires FLV. The audio and video branches start at slightly
+        // different times, starting the output at the first buffer instead of
+        // zero keeps the late one from being muxed with a jump
+        let rtmp_muxer = make_element("flvmux")?
+            .property("streamable", true)
+            .property_from_str("start-time-selection", "first")
+            .build()?;
+        if let Some(ms) = args.rtmp_mux_latency {
+            set_mux_latency(&rtmp_muxer, ms)?;
+        }
+        watch_muxed_timestamps(&rtmp_muxer)?;
+
+        // hold both inputs back to their running time, so the muxer gets them
+        // interleaved instead of in bursts from whichever branch ran ahead
+        let rtmp_videosync = make_sync_point()?;
+        let rtmp_audiosync = make_sync_point()?;
+
+        // every destination takes the same FLV, so the muxed stream is split once more
+        let rtmp_tee = make_element("tee")?
+            .property("allow-not-linked", true)
+            .build()?;
+
+        self.pipeline
+            .add_many(&[
+                &rtmp_videoqueue,
+                &rtmp_videoparse,
+                &rtmp_videosync,
+                &rtmp_audioqueue,
+                &rtmp_audiosync,
+                &rtmp_muxer,
+                &rtmp_tee,
+            ])
+            .context("adding the RTMP branch")?;
+
+        link_chain(
+            "RTMP video branch",
+            &[
+                videotee,
+                &rtmp_videoqueue,
+                &rtmp_videoparse,
+                &rtmp_videosync,
+                &rtmp_muxer,
+                &rtmp_tee,
+            ],
+        )?;
+        link_chain(
+            "RTMP audio branch",
+            &[audiotee, &rtmp_audioqueue, &rtmp_audiosync, &rtmp_muxer],
+        )?;
+
+        for destination in &destinations {
+            outputs.add(&self.pipeline, destination.clone(), &[(&rtmp_tee, "sink")])?;
+        }
+
+        Ok(RtmpBranch {
+            videoqueue: rtmp_videoqueue,
+            tee: rtmp_tee,
+            destinations,
+            locations: rtmp_locations,
+        })
+    }
+}
+
+/// Stops the producer and tears the receiver pipeline down when setting it up
+/// fails, along with the sources added meanwhile. Disarmed once it plays
+struct StartupGuard {
+    pipeline: gst::Pipeline,
+    sources: Vec<glib::SourceId>,
+    armed: bool,
+}
+
+impl StartupGuard {
+    /// The pipeline is playing, hand over the sources to remove once it's done
+    fn disarm(&mut self) -> Vec<glib::SourceId> {
+        self.armed = false;
+        std::mem::take(&mut self.sources)
+    }
+}
+
+impl Drop for StartupGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        for source in self.sources.drain(..) {
+            source.remove();
+        }
+        send_control(ControlMessage::Stop);
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// Decode the raw video from the producer, encode it and stream it over RTMP
+/// while recording it locally
+pub fn receiver(args: &ReceiveArgs) -> anyhow::Result<()> {
+    // a replayed file is read through fdsrc like stdin, from right after its header
+    let mut input_file = args
+        .input_file
+        .as_ref()
+        .map(|path| File::open(path).with_context(|| format!("opening {}", path.display())))
+        .transpose()?;
+
+    let video_info = if args.dry_run {
+        VideoInfo::dry_run()
+    } else if let Some(file) = &mut input_file {
+        recv_stream_videoinfo(file).context("reading video info from the input file")?
+    } else {
+        let mut input = open_data_fd(args.fd, FdDirection::Read)?;
+        recv_stream_videoinfo(&mut input).context("receiving video info from the producer")?
+    };
+    tracing::info!(?video_info, live = args.live(), "received video info");
+
+    let pulse_ready = args.dry_run
+        || args.test_source
+        || wait_for_pulse(
+            args.pulse_retries,
+            Duration::from_secs(args.pulse_retry_delay),
+        );
+
+    if !pulse_ready {
+        anyhow::ensure!(
+            args.silent_audio_fallback,
+            "PulseAudio is not available after {} retries",
+            args.pulse_retries
+        );
+        tracing::warn!("PulseAudio is not available, streaming silence instead");
+    }
+
+    let builder = PipelineBuilder::new(args, video_info)
+        .input_fd(match &input_file {
+            Some(file) => file.as_raw_fd(),
+            None => args.fd,
+        })
+        .silent_audio(!pulse_ready);
+
+    let ReceiverPipeline {
+        pipeline,
+        video,
+        audio,
+        outputs:
+            OutputBranches {
+                outputs,
+                recording,
+                rtmp,
+            },
+    } = builder.build()?;
+
+    if args.print_pipeline {
+        eprintln!("{}", launch_line(&pipeline));
+    }
+
+    if args.dry_run {
+        return dry_run(&pipeline);
+    }
+
+    // keep the RTMP outputs disconnected until the raw video is known to
+    // negotiate, so a bad producer header doesn't go live as a broken stream
+    let rtmp_block = match &rtmp {
+        Some(rtmp) => {
+            for bin in &rtmp.destinations {
+                bin.set_locked_state(true);
+            }
+
+            let tee_sinkpad = rtmp
+                .tee
+                .static_pad("sink")
+                .context("tee without sink pad")?;
+            tee_sinkpad
+                .add_probe(gst::PadProbeType::BLOCK_DOWNSTREAM, |_, _| {
+                    gst::PadProbeReturn::Ok
+                })
+                .map(|probe| (tee_sinkpad, probe))
+        }
+        None => None,
+    };
+
+    let mut startup = StartupGuard {
+        pipeline: pipeline.clone(),
+        sources: Vec::new(),
+        armed: true,
+    };
+    let sources = &mut startup.sources;
+
+    let main_loop = glib::MainLoop::new(None, false);
+    let pipeline_error: PipelineError = Default::default();
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout);
+
+    let already_exited = Arc::new(AtomicBool::new(false));
+
+    let shutdown = {
+        let pipeline = pipeline.clone();
+        let main_loop = main_loop.clone();
+        let pipeline_error = pipeline_error.clone();
+        let already_exited = already_exited.clone();
+
+        move || {
+            if !already_exited.swap(true, Ordering::Relaxed) {
+                send_control(ControlMessage::Stop);
+
+                // wait for the pipeline to send EOS
+                pipeline.send_event(gst::event::Eos::new());
+                arm_shutdown_deadline(&main_loop, &pipeline_error, shutdown_timeout);
+            }
+        }
+    };
+
+    // handled once the main loop runs, rather than killing the receiver
+    // while it waits for the raw video to negotiate
+    sources.extend([Signal::SIGTERM, Signal::SIGINT].map(|signal| {
+        let shutdown = shutdown.clone();
+
+        glib::unix_signal_add(signal as i32, move || {
+            tracing::debug!(%signal, "received signal");
+            shutdown();
+            glib::ControlFlow::Continue
+        })
+    }));
+
+    let encoder_stats = if args.stats_interval > 0 {
+        let encoder_stats = watch_encoder_output(&video.videoenc)?;
+        watch_av_offset(&encoder_stats, &video.tee, &audio.tee)?;
+
+        let json = match &args.stats_json {
+            Some(path) => Some(StatsJson::open(
+                path,
+                outputs.clone(),
+                rtmp.as_ref()
+                    .map(|rtmp| rtmp.destinations.clone())
+                    .unwrap_or_default(),
+            )?),
+            None => None,
+        };
+
+        sources.push(report_stats(
+            Duration::from_secs(args.stats_interval),
+            encoder_stats.clone(),
+            vec![video.rawvideoparsequeue.clone(), video.videoqueue.clone()],
+            json,
+        ));
+
+        Some(encoder_stats)
+    } else {
+        None
+    };
+
+    let rawvideoparse_srcpad = video
+        .rawvideoparse
+        .static_pad("src")
+        .context("rawvideoparse without src pad")?;
+
+    let caps = preflight_caps(&pipeline, &rawvideoparse_srcpad)
+        .context("raw video from the producer doesn't negotiate")?;
+    tracing::debug!(%caps, "raw video negotiated");
+
+    if let Some(rtmp) = &rtmp {
+        for bin in &rtmp.destinations {
+            bin.set_locked_state(false);
+            bin.sync_state_with_parent()
+                .context("starting RTMP destination")?;
+        }
+    }
+
+    if let Some((tee_sinkpad, probe)) = rtmp_block {
+        tee_sinkpad.remove_probe(probe);
+    }
+
+    if let Some((min_kbps, max_kbps)) = video.adaptive_bitrate {
+        match &rtmp {
+            Some(rtmp) => sources.push(adapt_bitrate(
+                video.encoder,
+                video.videoenc.clone(),
+                rtmp.videoqueue.clone(),
+                min_kbps,
+                max_kbps,
+            )),
+            None => tracing::warn!("not streaming over RTMP, ignoring --adaptive-bitrate"),
+        }
+    }
+
+    if args.heartbeat_interval > 0 {
+        sources.push(glib::timeout_add(
+            Duration::from_secs(args.heartbeat_interval),
+            || {
+                send_control(ControlMessage::Heartbeat);
+                glib::ControlFlow::Continue
+            },
+        ));
+    }
+
+    if args.producer_restart_timeout > 0 {
+        survive_producer_restart(
+            &video,
+            args.fd,
+            Duration::from_secs(args.producer_restart_timeout),
+            already_exited,
+        )?;
+    }
+
+    if args.stall_timeout > 0 {
+        sources.push(watch_for_stall(
+            &video.videoenc,
+            Duration::from_secs(args.stall_timeout),
+            &main_loop,
+            &pipeline_error,
+        )?);
+    }
+
+    if let Some(duration) = args.duration {
+        let duration = Duration::from_secs(duration);
+
+        // keeps firing until the loop quits, shutting down only ever happens once
+        sources.push(glib::timeout_add(duration, move || {
+            tracing::info!(?duration, "streamed for the requested duration, stopping");
+            shutdown();
+            glib::ControlFlow::Continue
+        }));
+    }
+
+    sources.push(glib::unix_signal_add(Signal::SIGUSR1 as i32, {
+        let recording = recording.clone();
+
+        move || {
+            tracing::debug!(signal = %Signal::SIGUSR1, "received signal");
+            recording.rotate();
+            glib::ControlFlow::Continue
+        }
+    }));
+
+    sources.push(glib::unix_signal_add(Signal::SIGHUP as i32, {
+        let recording = recording.clone();
+
+        move || {
+            tracing::debug!(signal = %Signal::SIGHUP, "received signal");
+            if let Err(err) = recording.toggle() {
+                tracing::error!("{:#}", err);
+            }
+            glib::ControlFlow::Continue
+        }
+    }));
+
+    let frozen = freeze_frame_on_demand(&video.videoenc)?;
+
+    sources.push(glib::unix_signal_add(Signal::SIGUSR2 as i32, move || {
+        tracing::debug!(signal = %Signal::SIGUSR2, "received signal");
+
+        if frozen.fetch_xor(true, Ordering::Relaxed) {
+            tracing::info!("resuming the stream");
+        } else {
+            tracing::info!("pausing the stream on the current frame");
+        }
+
+        glib::ControlFlow::Continue
+    }));
+
+    let bus = pipeline.bus().context("gstreamer pipeline without bus")?;
+
+    // stop feeding a failed output right from the streaming thread, before
+    // its error flows back through the tee and takes the whole stream down
+    bus.set_sync_handler({
+        let outputs = outputs.clone();
+
+        move |_, msg| {
+            if let MessageView::Error(..) = msg.view() {
+                outputs.mark_failed(msg);
+            }
+
+            gst::BusSyncReply::Pass
+        }
+    });
+
+    let _bus_watch = bus
+        .add_watch({
+            let main_loop = main_loop.clone();
+            let pipeline_error = pipeline_error.clone();
+            let pipeline = pipeline.clone();
+            let dump_dot_dir = args.dump_dot.clone();
+            let queues = [video.rawvideoparsequeue.clone(), video.videoqueue.clone()];
+            let recording = recording.clone();
+            let mut qos_dropped = BTreeMap::new();
+
+            move |_, msg| {
+                match msg.view() {
+                    MessageView::Eos(..) => {
+                        tracing::debug!("gstreamer reach EOS");
+                        main_loop.quit();
+                    }
+                    MessageView::Element(..) => recording.finish_stopping(msg),
+                    MessageView::StateChanged(state_changed)
+                        if msg.src() == Some(pipeline.upcast_ref()) =>
+                    {
+                        tracing::debug!(
+                            old = ?state_changed.old(),
+                            current = ?state_changed.current(),
+                            pending = ?state_changed.pending(),
+                            "pipeline state changed"
+                        );
+
+                        if state_changed.current() == gst::State::Playing {
+                            if let Some(dir) = &dump_dot_dir {
+                                dump_dot(&pipeline, dir, "receiver-playing");
+                            }
+
+                            report_latency(&pipeline, &queues);
+                        }
+                    }
+                    MessageView::Latency(..) => {
+                        // elements changed their latency, distribute the new one
+                        // before reporting it
+                        if let Err(err) = pipeline.recalculate_latency() {
+                            tracing::warn!(%err, "failed to recalculate latency");
+                        }
+
+                        report_latency(&pipeline, &queues);
+                    }
+                    MessageView::Error(err) => {
+                        let err = bus_error(msg, &err);
+
+                        if outputs.remove_failed(&pipeline, msg) {
+                            tracing::warn!("{:#}", err);
+                            tracing::warn!(
+                                output = %message_source(msg),
+                                "dropped failed output, keeping the others running"
+                            );
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        send_control(ControlMessage::Stop);
+                        tracing::error!("{:#}", err);
+
+                        if let Some(dir) = &dump_dot_dir {
+                            dump_dot(&pipeline, dir, "receiver-error");
+                        }
+
+                        pipeline_error.lock().unwrap().get_or_insert(err);
+                        main_loop.quit();
+                    }
+                    MessageView::Qos(qos) => {
+                        let (processed, dropped) = qos.stats();
+
+                        // undefined when the element doesn't count them
+                        if let (Some(stats), Ok(total)) =
+                            (&encoder_stats, u64::try_from(dropped.value()))
+                        {
+                            let element = msg
+                                .src()
+                                .map(|src| src.path_string().to_string())
+                                .unwrap_or_default();
+                            let dropped = qos_dropped_since(&mut qos_dropped, element, total);
+                            stats.dropped.fetch_add(dropped, Ordering::Relaxed);
+                        }
+
+                        tracing::warn!(
+                            element = %message_source(msg),
+                            %processed,
+                            %dropped,
+                            "element dropped buffers, the encoder may be overloaded"
+                        );
+                    }
+                    MessageView::Warning(warning) => {
+                        tracing::warn!("{}", bus_warning(msg, &warning));
+                    }
+                    _ => (),
+                }
+
+                glib::ControlFlow::Continue
+            }
+        })
+        .context("watching pipeline bus")?;
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("playing pipeline")?;
+    tracing::debug!("playing");
+    let sources = startup.disarm();
+
+    main_loop.run();
+
+    for source in sources {
+        source.remove();
+    }
+
+    // an error, or a drain stuck in another output, quits before EOS went
+    // through the whole pipeline
+    if pipeline_error.lock().unwrap().is_some() {
+        finalize_recording(
+            &bus,
+            &recording.file_output,
+            &recording.file_sink,
+            shutdown_timeout,
+        );
+    }
+
+    tracing::debug!("finishing pipeline");
+    pipeline.set_state(gst::State::Null)?;
+
+    match pipeline_error.lock().unwrap().take() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+pub fn test_rtmp(args: &TestRtmpArgs) -> anyhow::Result<()> {
+    if args.insecure_rtmp {
+        tracing::warn!("testing over unencrypted RTMP, the stream key is sent in the clear");
+    }
+
+    let key = read_secret_key(args.twitch_key_file.as_deref(), args.twitch_key_prompt)?
+        .context("no stream key, pass --twitch-key-file or --twitch-key-prompt")?;
+
+    let pipeline = gst::Pipeline::new();
+    let encoder = Encoder::select(Codec::H264);
+
+    let videosrc = make_element("videotestsrc")?
+        .property("is-live", true)
+        .build()?;
+    let videoconfig = make_element("capsfilter")?
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("width", 1280i32)
+                .field("height", 720i32)
+                .field("framerate", gst::Fraction::new(30, 1))
+                .build(),
+        )
+        .build()?;
+    let videoconvert = make_element("videoconvert")?.build()?;
+    let videoconvertconfig = make_element("capsfilter")?
+        .property(
+            "caps",
+            encoder.input_caps(&Colorimetry::Bt709.caps_string(ColorRange::Limited)),
+        )
+        .build()?;
+    let videoenc = encoder.make()?;
+    let videoparse = Codec::H264.make_parser()?;
+
+    let audiosrc = make_element("audiotestsrc")?
+        .property("is-live", true)
+        .property_from_str("wave", "silence")
+        .build()?;
+    let audioconvert = make_element("audioconvert")?.build()?;
+    let audioenc = make_element("fdkaacenc")?.build()?;
+
+    let muxer = make_element("flvmux")?
+        .property("streamable", true)
+        .build()?;
+    let sink = make_element("rtmp2sink")?
+        .property_from_str(
+            "location",
+            &twitch_url(&args.server, &args.rtmp_app, &key, args.insecure_rtmp)?,
+        )
+        .build()?;
+
+    pipeline
+        .add_many(&[
+            &videosrc,
+            &videoconfig,
+            &videoconvert,
+            &videoconvertconfig,
+            &videoenc,
+            &videoparse,
+            &audiosrc,
+            &audioconvert,
+            &audioenc,
+            &muxer,
+            &sink,
+        ])
+        .context("adding the test pipeline")?;
+
+    link_chain(
+        "test video chain",
+        &[
+            &videosrc,
+            &videoconfig,
+            &videoconvert,
+            &videoconvertconfig,
+            &videoenc,
+            &videoparse,
+            &muxer,
+            &sink,
+        ],
+    )?;
+    link_chain(
+        "test audio chain",
+        &[&audiosrc, &audioconvert, &audioenc, &muxer],
+    )?;
+
+    play(&pipeline)?;
+    tracing::info!(duration = args.duration, "streaming test pattern");
+
+    let bus = pipeline.bus().context("gstreamer pipeline without bus")?;
+    let result = match bus.timed_pop_filtered(
+        gst::ClockTime::from_seconds(args.duration),
+        &[gst::MessageType::Error],
+    ) {
+        Some(msg) => match msg.view() {
+            MessageView::Error(err) => Err(bus_error(&msg, &err)),
+            _ => unreachable!("filtered for errors"),
+        },
+        None => {
+            // the handshake alone succeeds with a wrong key on some ingests,
+            // acked bytes show the server took the stream
+            let stats = sink.property::<gst::Structure>("stats");
+            let acked = stats.get::<u64>("out-bytes-acked").unwrap_or_default();
+
+            if acked > 0 {
+                tracing::info!(acked, "RTMP server accepted the stream");
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("RTMP server acknowledged no data"))
+            }
+        }
+    };
+
+    pipeline.set_state(gst::State::Null)?;
+
+    result.context("testing RTMP server")
+}
+
+/// Outcome of encoding the test pattern at one resolution and bitrate
+struct BenchResult {
+    resolution: Resolution,
+    kbps: u32,
+    fps: f64,
+    /// Process CPU time over wall time, 100% being one core
+    cpu_percent: f64,
+}
+
+/// User plus system CPU time used by this process so far
+fn cpu_time() -> anyhow::Result<Duration> {
+    let usage = getrusage(UsageWho::RUSAGE_SELF).context("getrusage()")?;
+    let duration = |time: nix::sys::time::TimeVal| {
+        Duration::from_secs(time.tv_sec() as u64) + Duration::from_micros(time.tv_usec() as u64)
+    };
+
+    Ok(duration(usage.user_time()) + duration(usage.system_time()))
+}
+
+/// Encode a live test pattern into a `fakesink` for `duration`, counting the
+/// frames leaving the encoder and the CPU time spent meanwhile
+fn bench_step(
+    args: &BenchArgs,
+    encoder: Encoder,
+    resolution: Resolution,
+    kbps: u32,
+) -> anyhow::Result<BenchResult> {
+    let pipeline = gst::Pipeline::new();
+
+    // a moving pattern, a still one would encode to next to nothing
+    let videosrc = make_element("videotestsrc")?
+        .property("is-live", true)
+        .property_from_str("pattern", "ball")
+        .build()?;
+    let videoconfig = make_element("capsfilter")?
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("width", resolution.width)
+                .field("height", resolution.height)
+                .field("framerate", args.framerate)
+                .build(),
+        )
+        .build()?;
+    let videoconvert = make_element("videoconvert")?.build()?;
+    let videoconvertconfig = make_element("capsfilter")?
+        .property(
+            "caps",
+            encoder.input_caps(&Colorimetry::Bt709.caps_string(ColorRange::Limited)),
+        )
+        .build()?;
+    let videoenc = encoder.make()?;
+    encoder.set_bitrate_kbps(&videoenc, kbps);
+    let sink = make_element("fakesink")?.property("sync", false).build()?;
+
+    pipeline
+        .add_many(&[
+            &videosrc,
+            &videoconfig,
+            &videoconvert,
+            &videoconvertconfig,
+            &videoenc,
+            &sink,
+        ])
+        .context("adding the benchmark pipeline")?;
+    link_chain(
+        "benchmark pipeline",
+        &[
+            &videosrc,
+            &videoconfig,
+            &videoconvert,
+            &videoconvertconfig,
+            &videoenc,
+            &sink,
+        ],
+    )?;
+
+    let stats = watch_encoder_output(&videoenc)?;
+
+    play(&pipeline)?;
+
+    let frames_before = stats.frames.load(Ordering::Relaxed);
+    let cpu_before = cpu_time()?;
+    let started = Instant::now();
+
+    let bus = pipeline.bus().context("gstreamer pipeline without bus")?;
+    let result = match bus.timed_pop_filtered(
+        gst::ClockTime::from_seconds(args.duration),
+        &[gst::MessageType::Error],
+    ) {
+        Some(msg) => match msg.view() {
+            MessageView::Error(err) => Err(bus_error(&msg, &err)),
+            _ => unreachable!("filtered for errors"),
+        },
+        None => {
+            let elapsed = started.elapsed().as_secs_f64();
+            let frames = stats.frames.load(Ordering::Relaxed) - frames_before;
+            let cpu = (cpu_time()? - cpu_before).as_secs_f64();
+
+            Ok(BenchResult {
+                resolution,
+                kbps,
+                fps: frames as f64 / elapsed,
+                cpu_percent: cpu / elapsed * 100.0,
+            })
+        }
+    };
+
+    pipeline.set_state(gst::State::Null)?;
+
+    result.with_context(|| format!("benchmarking {} at {} kbit/s", resolution, kbps))
+}
+
+/// Encode a test pattern at each resolution and bitrate in turn and print the
+/// framerate reached and CPU used, to tell which settings the machine sustains
+pub fn bench(args: &BenchArgs) -> anyhow::Result<()> {
+    let encoder = Encoder::select(args.codec);
+    let target_fps = f64::from(args.framerate.numer()) / f64::from(args.framerate.denom());
+
+    tracing::info!(
+        encoder = encoder.factory_name(),
+        duration = args.duration,
+        "benchmarking"
+    );
+
+    let mut results = Vec::new();
+    for &resolution in &args.resolutions {
+        for &kbps in &args.bitrates {
+            let result = bench_step(args, encoder, resolution, kbps)?;
+            tracing::info!(
+                %resolution,
+                kbps,
+                fps = result.fps,
+                cpu = result.cpu_percent,
+                "benchmark step done"
+            );
+            results.push(result);
+        }
+    }
+
+    println!("encoder: {}", encoder.factory_name());
+    println!(
+        "{:>10}  {:>8}  {:>7}  {:>6}",
+        "resolution", "kbit/s", "fps", "cpu%"
+    );
+    for result in &results {
+        // a few frames short is startup, more means the encoder falls behind
+        let marker = if result.fps < target_fps * 0.95 {
+            "  too slow"
+        } else {
+            ""
+        };
+        println!(
+            "{:>10}  {:>8}  {:>7.1}  {:>6.0}{}",
+            result.resolution.to_string(),
+            result.kbps,
+            result.fps,
+            result.cpu_percent,
+            marker
+        );
+    }
+
+    Ok(())
+}
+
+/// Print whether each GStreamer element the receiver needs with `args` is
+/// installed, failing when any is missing, so a missing plugin shows before
+/// going live rather than when the stream starts
+pub fn check(args: &ReceiveArgs) -> anyhow::Result<()> {
+    let builder = PipelineBuilder::new(args, VideoInfo::dry_run());
+
+    let mut missing = 0;
+    for name in builder.required_elements() {
+        if gst::ElementFactory::find(name).is_some() {
+            println!("ok       {}", name);
+        } else {
+            println!("missing  {}, {}", name, install_hint(name));
+            missing += 1;
+        }
+    }
+
+    anyhow::ensure!(missing == 0, "{} GStreamer element(s) missing", missing);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches, Parser};
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct ReceiveCli {
+        #[command(flatten)]
+        args: ReceiveArgs,
+    }
+
+    #[derive(Parser)]
+    struct TestRtmpCli {
+        #[command(flatten)]
+        args: TestRtmpArgs,
+    }
+
+    #[test]
+    fn qos_dropped_counts_each_buffer_once() {
+        let mut last = BTreeMap::new();
+
+        assert_eq!(qos_dropped_since(&mut last, "a".into(), 3), 3);
+        assert_eq!(qos_dropped_since(&mut last, "a".into(), 5), 2);
+        assert_eq!(qos_dropped_since(&mut last, "b".into(), 1), 1);
+        assert_eq!(qos_dropped_since(&mut last, "a".into(), 5), 0);
+    }
+
+    #[test]
+    fn test_rtmp_key_stays_off_the_command_line() {
+        let parse = |args: &[&str]| {
+            TestRtmpCli::try_parse_from(["test-rtmp", "live.twitch.tv"].iter().chain(args))
+        };
+
+        assert!(parse(&["live_123_secret"]).is_err());
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--twitch-key-file", "key.txt"]).is_ok());
+        assert!(parse(&["--twitch-key-prompt"]).is_ok());
+        assert!(parse(&["--twitch-key-file", "key.txt", "--twitch-key-prompt"]).is_err());
+    }
+
+    #[test]
+    fn stats_json_needs_stats_interval() {
+        let parse = |args: &[&str]| {
+            ReceiveCli::try_parse_from(["receive", "--local-only"].iter().chain(args))
+        };
+
+        assert!(parse(&["--stats-json", "stats.json"]).is_err());
+        assert!(parse(&["--stats-json", "stats.json", "--stats-interval", "5"]).is_ok());
+    }
+
+    #[test]
+    fn videoinfo_round_trip() {
+        let video_info = VideoInfo {
+            width: 1920,
+            height: 1080,
+            format: "BGRx".into(),
+            framerate: gst::Fraction::new(60000, 1001),
+            colorimetry: "sRGB".into(),
+            frame_size: 1920 * 1080 * 4,
+            planes: vec![PlaneLayout {
+                offset: 0,
+                stride: 1920 * 4,
+            }],
+        };
+
+        let mut buffer = Vec::new();
+        send_stream_videoinfo(&mut buffer, &video_info).unwrap();
+        let received = recv_stream_videoinfo(&mut buffer.as_slice()).unwrap();
+
+        assert_eq!(received.width, 1920);
+        assert_eq!(received.height, 1080);
+        assert_eq!(received.format, "BGRx");
+        assert_eq!(received.framerate, gst::Fraction::new(60000, 1001));
+        assert_eq!(received.colorimetry, "sRGB");
+    }
+
+    #[test]
+    fn header_splitter_finds_headers_between_frames() {
+        let header = |width: i32| {
+            let mut header = Vec::new();
+            send_stream_videoinfo(
+                &mut header,
+                &VideoInfo {
+                    width,
+                    height: 1,
+                    format: "BGRx".into(),
+                    framerate: gst::Fraction::new(25, 1),
+                    colorimetry: "sRGB".into(),
+                    frame_size: width as u32 * 4,
+                    planes: Vec::new(),
+                },
+            )
+            .unwrap();
+            header
+        };
+
+        // a frame starting like a header, without being one, stays a frame
+        let mut frames = Vec::new();
+        frames.extend_from_slice(b"QSVIQSVI");
+        frames.extend_from_slice(&[1; 8]);
+        let old_len = frames.len();
+        frames.extend_from_slice(&[2; 16]);
+        frames.extend_from_slice(&[3; 16]);
+
+        let mut stream = frames[..old_len].to_vec();
+        stream.extend(header(4));
+        stream.extend_from_slice(&frames[old_len..]);
+
+        let mut splitter = HeaderSplitter::new(NonZeroU32::new(8).unwrap());
+        let mut passed = Vec::new();
+        let mut headers = Vec::new();
+        for chunk in stream.chunks(3) {
+            let mut split = splitter.push(chunk);
+            loop {
+                passed.extend_from_slice(split.bytes.as_deref().unwrap_or(chunk));
+                match split.header {
+                    Some(video_info) => {
+                        headers.push((passed.len(), video_info.width));
+                        split = splitter.push(&[]);
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        assert_eq!(passed, frames);
+        assert_eq!(headers, [(old_len, 4)]);
+    }
+
+    #[test]
+    fn videoinfo_strides_round_trip() {
+        // a 1366 pixels wide BGRx capture with its rows padded to 64 bytes
+        let planes = vec![PlaneLayout {
+            offset: 0,
+            stride: 5504,
+        }];
+        let video_info = VideoInfo {
+            width: 1366,
+            height: 768,
+            format: "BGRx".into(),
+            framerate: gst::Fraction::new(25, 1),
+            colorimetry: "sRGB".into(),
+            frame_size: 5504 * 768,
+            planes: planes.clone(),
+        };
+
+        let mut buffer = Vec::new();
+        send_stream_videoinfo(&mut buffer, &video_info).unwrap();
+        let received = recv_stream_videoinfo(&mut buffer.as_slice()).unwrap();
+
+        assert_eq!(received.frame_size, 5504 * 768);
+        assert_eq!(received.planes, planes);
+
+        // the last byte is the plane count when there are no planes
+        let mut buffer = Vec::new();
+        send_stream_videoinfo(
+            &mut buffer,
+            &VideoInfo {
+                planes: Vec::new(),
+                ..video_info
+            },
+        )
+        .unwrap();
+        *buffer.last_mut().unwrap() = MAX_PLANES as u8 + 1;
+        assert!(matches!(
+            recv_stream_videoinfo(&mut buffer.as_slice()),
+            Err(ProtocolError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn recv_videoinfo_rejects_zero_frame_size() {
+        let mut buffer = Vec::new();
+        send_stream_videoinfo(
+            &mut buffer,
+            &VideoInfo {
+                width: 1280,
+                height: 720,
+                format: "BGRx".into(),
+                framerate: gst::Fraction::new(25, 1),
+                colorimetry: "sRGB".into(),
+                frame_size: 0,
+                planes: Vec::new(),
+            },
+        )
+        .unwrap();
+
+        assert!(matches!(
+            recv_stream_videoinfo(&mut buffer.as_slice()),
+            Err(ProtocolError::InvalidValue("zero frame size"))
+        ));
+    }
+
+    #[test]
+    fn recv_videoinfo_rejects_truncated_buffer() {
+        let video_info = VideoInfo {
+            width: 1280,
+            height: 720,
+            format: "I420".into(),
+            framerate: gst::Fraction::new(30, 1),
+            colorimetry: "bt709".into(),
+            frame_size: 1280 * 720 * 3 / 2,
+            planes: vec![
+                PlaneLayout {
+                    offset: 0,
+                    stride: 1280,
+                },
+                PlaneLayout {
+                    offset: 1280 * 720,
+                    stride: 640,
+                },
+                PlaneLayout {
+                    offset: 1280 * 720 * 5 / 4,
+                    stride: 640,
+                },
+            ],
+        };
+
+        let mut buffer = Vec::new();
+        send_stream_videoinfo(&mut buffer, &video_info).unwrap();
+
+        for len in 0..buffer.len() {
+            assert!(matches!(
+                recv_stream_videoinfo(&mut &buffer[..len]),
+                Err(ProtocolError::TruncatedHeader(_))
+            ));
+        }
+
+        buffer[0] = b'X';
+        assert!(matches!(
+            recv_stream_videoinfo(&mut buffer.as_slice()),
+            Err(ProtocolError::BadMagic(_))
+        ));
+    }
+
+    #[test]
+    fn control_message_round_trip() {
+        for message in [ControlMessage::Stop, ControlMessage::Heartbeat] {
+            let mut buffer = Vec::new();
+            message.write_to(&mut buffer).unwrap();
+            assert_eq!(
+                ControlMessage::read_from(&mut buffer.as_slice()).unwrap(),
+                Some(message)
+            );
+        }
+    }
+
+    #[test]
+    fn control_message_skips_spurious_bytes() {
+        let mut src: &[u8] = b"xyz\n";
+        assert_eq!(
+            ControlMessage::read_from(&mut src).unwrap(),
+            Some(ControlMessage::Stop)
+        );
+        assert_eq!(ControlMessage::read_from(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn gop_size_follows_framerate() {
+        assert_eq!(gop_size(2, gst::Fraction::new(30, 1)), 60);
+        assert_eq!(gop_size(2, gst::Fraction::new(60000, 1001)), 120);
+        assert_eq!(gop_size(1, gst::Fraction::new(1, 2)), 1);
+    }
+
+    #[test]
+    fn twitch_url_normalizes_server() {
+        let expected = "rtmps://live.twitch.tv/app/key";
+
+        assert_eq!(
+            twitch_url("live.twitch.tv", "app", "key", false).unwrap(),
+            expected
+        );
+        assert_eq!(
+            twitch_url("rtmps://live.twitch.tv", "app", "key", false).unwrap(),
+            expected
+        );
+        assert_eq!(
+            twitch_url("live.twitch.tv/", "app", "key", false).unwrap(),
+            expected
+        );
+        assert_eq!(
+            twitch_url("rtmps://live.twitch.tv/app/", "app", "key", false).unwrap(),
+            expected
+        );
+        assert_eq!(
+            twitch_url(" live.twitch.tv ", "app", " key\n", false).unwrap(),
+            expected
+        );
+        assert_eq!(
+            twitch_url("live.twitch.tv:443", "app", "key", false).unwrap(),
+            "rtmps://live.twitch.tv:443/app/key"
+        );
+        assert_eq!(
+            twitch_url("rtmp://ingest.local:1935", "app", "key", true).unwrap(),
+            "rtmp://ingest.local:1935/app/key"
+        );
+    }
+
+    #[test]
+    fn twitch_url_takes_any_application_path() {
+        assert_eq!(
+            twitch_url("a.rtmp.youtube.com/live2", "live2", "key", false).unwrap(),
+            "rtmps://a.rtmp.youtube.com/live2/key"
+        );
+        assert_eq!(
+            twitch_url("ingest.local", "/stream/live/", "key", false).unwrap(),
+            "rtmps://ingest.local/stream/live/key"
+        );
+        assert_eq!(
+            twitch_url("ingest.local", "", "key", false).unwrap(),
+            "rtmps://ingest.local/key"
+        );
+        assert!(twitch_url("ingest.local/app", "", "key", false).is_err());
+    }
+
+    #[test]
+    fn twitch_url_rejects_malformed_input() {
+        assert!(twitch_url("", "app", "key", false).is_err());
+        assert!(twitch_url("https://live.twitch.tv", "app", "key", false).is_err());
+        assert!(twitch_url("live.twitch.tv/other/path", "app", "key", false).is_err());
+        assert!(twitch_url("live twitch tv", "app", "key", false).is_err());
+        assert!(twitch_url("live.twitch.tv:port", "app", "key", false).is_err());
+        assert!(twitch_url("live.twitch.tv", "app", "", false).is_err());
+        assert!(twitch_url("live.twitch.tv", "app", "app/key", false).is_err());
+        assert!(twitch_url("rtmp://live.twitch.tv", "app", "key", false).is_err());
+        assert!(twitch_url("rtmps://live.twitch.tv", "app", "key", true).is_err());
+        assert!(twitch_url("live.twitch.tv", "a?pp", "key", false).is_err());
+
+        assert!(parse_rtmp_url("rtmp://example.com/live/key").is_ok());
+        assert!(parse_rtmp_url("example.com/live/key").is_err());
+        assert!(parse_rtmp_url("http://example.com/live/key").is_err());
+        assert!(parse_rtmp_url("rtmp://example.com/").is_err());
+    }
+
+    #[test]
+    fn launch_properties_are_shell_quoted() {
+        assert_eq!(launch_property("fd", "3"), "fd=3");
+        assert_eq!(
+            launch_property("caps", "video/x-raw, format=(string)I420"),
+            "'caps=\"video/x-raw, format=(string)I420\"'"
+        );
+        assert_eq!(
+            launch_property("text", "it's \"live\""),
+            "'text=\"it'\\''s \\\"live\\\"\"'"
+        );
+    }
+
+    #[test]
+    fn h264_level_limits() {
+        let resolution = Resolution {
+            width: 1920,
+            height: 1080,
+        };
+        let framerate = gst::Fraction::new(60, 1);
+
+        assert!(
+            h264_level_violations(H264Level::L4_2, None, resolution, framerate, 6000).is_empty()
+        );
+        // 8160 macroblocks fit, but not 60 times a second
+        assert_eq!(
+            h264_level_violations(H264Level::L4_1, None, resolution, framerate, 6000).len(),
+            1
+        );
+        assert_eq!(
+            h264_level_violations(H264Level::L3_1, None, resolution, framerate, 6000).len(),
+            2
+        );
+        // high profile allows a quarter more bitrate
+        assert!(
+            h264_level_violations(
+                H264Level::L4,
+                Some(H264Profile::High),
+                Resolution {
+                    width: 1280,
+                    height: 720
+                },
+                framerate,
+                25000
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn missing_element_names_the_plugin_to_install() {
+        gst::init().unwrap();
+
+        let err = make_element("nosuchelement").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "GStreamer element nosuchelement is missing, check the plugin providing it is installed"
+        );
+        assert_eq!(
+            MissingElement::new("rtmp2sink").to_string(),
+            "GStreamer element rtmp2sink is missing, install the package shipping the rtmp2 plugin \
+             from gst-plugins-bad"
+        );
+    }
+
+    #[test]
+    fn launch_line_lists_changed_properties_and_links() {
+        gst::init().unwrap();
+
+        let pipeline = gst::Pipeline::new();
+        let src = gst::ElementFactory::make("fakesrc")
+            .name("src")
+            .property("num-buffers", 1i32)
+            .build()
+            .unwrap();
+        let bin = gst::Bin::builder().name("bin").build();
+        let queue = gst::ElementFactory::make("queue")
+            .name("queue")
+            .build()
+            .unwrap();
+        let sink = gst::ElementFactory::make("fakesink")
+            .name("sink")
+            .build()
+            .unwrap();
+
+        bin.add_many(&[&queue, &sink]).unwrap();
+        queue.link(&sink).unwrap();
+        add_ghost_sink_pad(&bin, &queue, "sink").unwrap();
+        pipeline.add_many(&[&src, bin.upcast_ref()]).unwrap();
+        src.link(&bin).unwrap();
+
+        let line = launch_line(&pipeline);
+
+        assert!(
+            line.starts_with("gst-launch-1.0 fakesrc name=src "),
+            "{}",
+            line
+        );
+        assert!(line.contains(" num-buffers=1 "), "{}", line);
+        assert!(line.contains(" src.src ! queue.sink "), "{}", line);
+        assert!(line.ends_with(" queue.src ! sink.sink"), "{}", line);
+    }
+
+    #[test]
+    fn launch_line_redacts_rtmp_stream_keys() {
+        if !has_elements(&["rtmp2sink"]) {
+            return;
+        }
+
+        let pipeline = gst::Pipeline::new();
+        let sink = gst::ElementFactory::make("rtmp2sink")
+            .name("sink")
+            .property("location", "rtmps://live.twitch.tv/app/live_123_twitchkey")
+            .build()
+            .unwrap();
+        pipeline.add(&sink).unwrap();
+
+        let line = launch_line(&pipeline);
+
+        assert!(line.contains(" 'stream=\"<redacted>\"'"), "{}", line);
+        assert!(!line.contains("live_123_twitchkey"), "{}", line);
+    }
+
+    #[test]
+    fn dot_graph_redacts_rtmp_stream_keys() {
+        if !has_elements(&["rtmp2sink"]) {
+            return;
+        }
+
+        let pipeline = gst::Pipeline::new();
+        let sink = gst::ElementFactory::make("rtmp2sink")
+            .property("location", "rtmps://live.twitch.tv/app/live_123_twitchkey")
+            .build()
+            .unwrap();
+        pipeline.add(&sink).unwrap();
+
+        let graph = dot_graph(&pipeline);
+
+        assert!(graph.contains("rtmp2sink"), "{}", graph);
+        assert!(!graph.contains("live_123_twitchkey"), "{}", graph);
+
+        // bus messages only name the location
+        assert_eq!(
+            redact_rtmp_urls(
+                "rtmp2sink0: could not connect to rtmps://live.twitch.tv/app/secret (timeout)"
+            ),
+            "rtmp2sink0: could not connect to rtmps://live.twitch.tv/app/<redacted> (timeout)"
+        );
+        assert_eq!(
+            redact_rtmp_urls("could not connect to rtmps://example.com/secret"),
+            "could not connect to rtmps://example.com/<redacted>"
+        );
+    }
+
+    #[test]
+    fn frozen_frame_follows_caps_changes() {
+        if !has_elements(&["identity", "fakesink"]) {
+            return;
+        }
+
+        let pipeline = gst::Pipeline::new();
+        let videoenc = make_element("identity").unwrap().build().unwrap();
+        let sink = make_element("fakesink")
+            .unwrap()
+            .property("sync", false)
+            .property("async", false)
+            .build()
+            .unwrap();
+        pipeline.add_many([&videoenc, &sink]).unwrap();
+        videoenc.link(&sink).unwrap();
+
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        sink.static_pad("sink")
+            .unwrap()
+            .add_probe(gst::PadProbeType::BUFFER, {
+                let sizes = sizes.clone();
+
+                move |_, info| {
+                    if let Some(gst::PadProbeData::Buffer(buffer)) = &info.data {
+                        sizes.lock().unwrap().push(buffer.size());
+                    }
+                    gst::PadProbeReturn::Ok
+                }
+            });
+
+        let frozen = freeze_frame_on_demand(&videoenc).unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        let sinkpad = videoenc.static_pad("sink").unwrap();
+        let caps = |width: i32| {
+            gst::event::Caps::new(
+                &gst::Caps::builder("video/x-raw")
+                    .field("width", width)
+                    .build(),
+            )
+        };
+        let push = |size| {
+            sinkpad
+                .chain(gst::Buffer::with_size(size).unwrap())
+                .unwrap();
+        };
+
+        sinkpad.send_event(gst::event::StreamStart::new("frozen"));
+        sinkpad.send_event(caps(1));
+        sinkpad.send_event(gst::event::Segment::new(&gst::FormattedSegment::<
+            gst::ClockTime,
+        >::new()));
+
+        push(1);
+        frozen.store(true, Ordering::Relaxed);
+        push(2);
+        // the frame held before is of the old size, the next one takes over
+        sinkpad.send_event(caps(2));
+        push(3);
+        push(4);
+
+        pipeline.set_state(gst::State::Null).unwrap();
+        assert_eq!(*sizes.lock().unwrap(), [1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn parse_monitor_region_by_index_or_name() {
+        let listing = "Monitors: 2\n \
+                       0: +*eDP-1 1920/344x1080/194+0+0  eDP-1\n \
+                       1: +DP-1 2560/597x1440/336+1920+0  DP-1\n";
+        let external = Region {
+            x: 1920,
+            y: 0,
+            width: 2560,
+            height: 1440,
+        };
+
+        assert_eq!(parse_monitor_region(listing, "1"), Some(external));
+        assert_eq!(parse_monitor_region(listing, "DP-1"), Some(external));
+        assert_eq!(
+            parse_monitor_region(listing, "0").map(|region| region.width),
+            Some(1920)
+        );
+        assert_eq!(parse_monitor_region(listing, "HDMI-1"), None);
+    }
+
+    #[test]
+    fn parse_capture_sources() {
+        assert_eq!(parse_capture_source("x11"), Ok(CaptureSource::X11));
+        assert_eq!(
+            parse_capture_source("v4l2:/dev/video2"),
+            Ok(CaptureSource::V4l2("/dev/video2".into()))
+        );
+        assert_eq!(
+            parse_capture_source("v4l2"),
+            Ok(CaptureSource::V4l2("/dev/video0".into()))
+        );
+        assert!(parse_capture_source("v4l2:").is_err());
+        assert!(parse_capture_source("x11:0").is_err());
+        assert!(parse_capture_source("webcam").is_err());
+    }
+
+    /// The command line of the binary, options overriding earlier occurrences
+    #[derive(Parser)]
+    #[command(args_override_self = true)]
+    struct Cli {
+        #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+        verbose: u8,
+
+        #[arg(long, global = true)]
+        config: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: CliCommands,
+    }
+
+    #[derive(clap::Subcommand)]
+    enum CliCommands {
+        Receive(ReceiveArgs),
+    }
+
+    #[test]
+    fn config_file_options_precede_the_command_line() {
+        let path = std::env::temp_dir().join(format!(
+            "qubes-streaming-config-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "twitch_server = \"file.example.com\"\n\
+             twitch_key = \"filekey\"\n\
+             insecure-rtmp = true\n\
+             bitrate = 3000\n\
+             eq-bands = [1, -2.5, 0, 0, 0, 0, 0, 0, 0, 0]\n\
+             rtmp-url = [\"rtmps://file.example.com/app/key\"]\n\
+             low-latency = false\n",
+        )
+        .unwrap();
+
+        let args = [
+            "qubes-streaming",
+            "-v",
+            "receive",
+            "--config",
+            path.to_str().unwrap(),
+            "--bitrate=4500",
+            "--insecure-rtmp",
+            "--eq-bands",
+            "-1,0,0,0,0,0,0,0,0,0",
+            "cli.example.com",
+        ]
+        .map(OsString::from)
+        .to_vec();
+        let expanded = expand_config_args(&Cli::command(), args).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let matches = Cli::command().try_get_matches_from(expanded).unwrap();
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        let CliCommands::Receive(args) = cli.command;
+
+        assert_eq!(cli.verbose, 1);
+        assert_eq!(cli.config, Some(path));
+        assert_eq!(args.bitrate, Some(4500));
+        assert!(args.insecure_rtmp);
+        assert_eq!(
+            args.eq_bands,
+            [-1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+        );
+        assert_eq!(args.twitch_server.as_deref(), Some("cli.example.com"));
+        // the command line doesn't set these, so the file does
+        assert_eq!(args.twitch_key.as_deref(), Some("filekey"));
+        assert_eq!(args.rtmp_urls, ["rtmps://file.example.com/app/key"]);
+    }
+
+    #[test]
+    fn colorimetry_caps_strings() {
+        assert_eq!(
+            Colorimetry::Bt709.caps_string(ColorRange::Limited),
+            "2:3:5:1"
+        );
+        assert_eq!(Colorimetry::Bt709.caps_string(ColorRange::Full), "1:3:5:1");
+        assert_eq!(Colorimetry::Bt601.caps_string(ColorRange::Full), "1:4:5:4");
+    }
+
+    #[test]
+    fn resolution_fit_keeps_aspect_ratio() {
+        let hd = Resolution {
+            width: 1920,
+            height: 1080,
+        };
+
+        assert_eq!(hd.fit(3840, 2160), hd);
+        assert_eq!(
+            hd.fit(2560, 1600),
+            Resolution {
+                width: 1728,
+                height: 1080
+            }
+        );
+        assert_eq!(
+            hd.fit(5120, 1440),
+            Resolution {
+                width: 1920,
+                height: 540
+            }
+        );
+        assert!(parse_resolution("1920x").is_err());
+        assert_eq!(
+            parse_resolution("1280x720"),
+            Ok(Resolution {
+                width: 1280,
+                height: 720
+            })
+        );
+    }
+
+    #[test]
+    fn recv_videoinfo_rejects_huge_format_len() {
+        let mut header = Vec::new();
+        header.extend_from_slice(&VIDEOINFO_MAGIC);
+        header.push(PROTOCOL_VERSION);
+        header.extend_from_slice(&1920u32.to_be_bytes());
+        header.extend_from_slice(&1080u32.to_be_bytes());
+        header.extend_from_slice(&25u32.to_be_bytes());
+        header.extend_from_slice(&1u32.to_be_bytes());
+        header.extend_from_slice(&u32::MAX.to_be_bytes());
+
+        let err = recv_stream_videoinfo(&mut header.as_slice()).unwrap_err();
+        assert!(matches!(
+            err,
+            ProtocolError::FormatTooLong {
+                field: "format",
+                ..
+            }
+        ));
+    }
+
+    /// Plugins the H.264 receiver pipeline is built from, besides the encoder
+    const RECEIVER_FACTORIES: [&str; 19] = [
+        "fdsrc",
+        "queue",
+        "capsfilter",
+        "rawvideoparse",
+        "videorate",
+        "videoconvert",
+        "tee",
+        "pulsesrc",
+        "audioconvert",
+        "audiocheblimit",
+        "equalizer-10bands",
+        "audioresample",
+        "fdkaacenc",
+        "h264parse",
+        "splitmuxsink",
+        "flvmux",
+        "rtmp2sink",
+        "videoscale",
+        "identity",
+    ];
+
+    /// Set to skip the tests whose GStreamer elements are missing, rather than fail them
+    const SKIP_MISSING_ENV: &str = "QUBES_STREAMING_SKIP_MISSING_PLUGINS";
+
+    /// Whether GStreamer has all of `factories`. Missing ones fail the test,
+    /// unless skipping is asked for
+    fn has_elements(factories: &[&str]) -> bool {
+        gst::init().unwrap();
+
+        let missing = factories
+            .iter()
+            .filter(|name| gst::ElementFactory::find(name).is_none())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return true;
+        }
+
+        assert!(
+            std::env::var_os(SKIP_MISSING_ENV).is_some(),
+            "missing GStreamer elements {:?}, set {} to skip the tests needing them",
+            missing,
+            SKIP_MISSING_ENV
+        );
+        eprintln!("skipping, missing GStreamer elements {:?}", missing);
+
+        false
+    }
+
+    /// Factory names of the top level elements of the receiver pipeline built
+    /// from `args`, or None when GStreamer lacks some of the plugins
+    fn receiver_elements(args: &[&str]) -> Option<(gst::Pipeline, Vec<String>)> {
+        gst::init().unwrap();
+
+        let encoder = Encoder::select(Codec::H264).factory_name();
+        if !has_elements(&[&RECEIVER_FACTORIES[..], &[encoder]].concat()) {
+            return None;
+        }
+
+        let args = ReceiveCli::try_parse_from(["receive", "--dry-run"].iter().chain(args))
+            .unwrap()
+            .args;
+
+        let built = PipelineBuilder::new(&args, VideoInfo::dry_run())
+            .build()
+            .unwrap();
+
+        let mut elements = built
+            .pipeline
+            .iterate_elements()
+            .into_iter()
+            .map(|element| {
+                let element = element.unwrap();
+                match element.factory() {
+                    Some(factory) => factory.name().to_string(),
+                    None => element.name().to_string(),
+                }
+            })
+            .collect::<Vec<_>>();
+        elements.sort();
+
+        Some((built.pipeline, elements))
+    }
+
+    fn sorted(names: &[&str]) -> Vec<String> {
+        let mut names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn local_only_pipeline_elements() {
+        let Some((_, elements)) = receiver_elements(&["--local-only"]) else {
+            return;
+        };
+
+        let encoder = Encoder::select(Codec::H264).factory_name();
+        assert_eq!(
+            elements,
+            sorted(&[
+                // video
+                "fdsrc",
+                "queue",
+                "capsfilter",
+                "rawvideoparse",
+                "capsfilter",
+                "videorate",
+                "capsfilter",
+                "videoconvert",
+                "capsfilter",
+                "queue",
+                encoder,
+                "tee",
+                // audio
+                "pulsesrc",
+                "audioconvert",
+                "audiocheblimit",
+                "audioconvert",
+                "equalizer-10bands",
+                "audioresample",
+                "capsfilter",
+                "queue",
+                "fdkaacenc",
+                "tee",
+                // outputs
+                "file-output",
+            ])
+        );
+    }
+
+    #[test]
+    fn rtmp_pipeline_elements() {
+        let Some((pipeline, elements)) = receiver_elements(&[
+            "live.twitch.tv",
+            "secret",
+            "--rtmp-url",
+            "rtmps://backup.example.com/app/secret",
+            "--no-audio-filters",
+            "--output-resolution",
+            "1280x720",
+            "--av-offset",
+            "100",
+        ]) else {
+            return;
+        };
+
+        let encoder = Encoder::select(Codec::H264).factory_name();
+        assert_eq!(
+            elements,
+            sorted(&[
+                // video
+                "fdsrc",
+                "queue",
+                "capsfilter",
+                "rawvideoparse",
+                "capsfilter",
+                "videorate",
+                "capsfilter",
+                "videoconvert",
+                "capsfilter",
+                "videoscale",
+                "capsfilter",
+                "queue",
+                encoder,
+                "tee",
+                // audio
+                "pulsesrc",
+                "audioconvert",
+                "audioresample",
+                "capsfilter",
+                "queue",
+                "fdkaacenc",
+                "identity",
+                "tee",
+                // outputs
+                "file-output",
+                "queue",
+                "h264parse",
+                "identity",
+                "queue",
+                "identity",
+                "flvmux",
+                "tee",
+                "rtmp-destination-0",
+                "rtmp-destination-1",
+            ])
+        );
+
+        for name in ["rtmp-destination-0", "rtmp-destination-1"] {
+            let destination = pipeline
+                .by_name(name)
+                .unwrap()
+                .downcast::<gst::Bin>()
+                .unwrap();
+            assert!(
+                destination
+                    .iterate_elements()
+                    .into_iter()
+                    .any(|element| element.unwrap().factory().unwrap().name() == "rtmp2sink")
+            );
+        }
+    }
+
+    #[test]
+    fn required_elements_cover_the_built_pipeline() {
+        gst::init().unwrap();
+
+        let hls_dir =
+            std::env::temp_dir().join(format!("qubes-streaming-hls-{}", std::process::id()));
+        let configs: [&[&str]; 7] = [
+            &[
+                "live.twitch.tv",
+                "secret",
+                "--output-resolution",
+                "1280x720",
+                "--av-offset",
+                "-100",
+            ],
+            &["--local-only"],
+            &["--local-only", "--hls-dir", hls_dir.to_str().unwrap()],
+            &["--local-only", "--record-format", "webm"],
+            &["--local-only", "--no-audio-filters"],
+            &["--local-only", "--audio-sync-to-video"],
+            &["--local-only", "--codec", "h265"],
+        ];
+
+        for config in configs {
+            let args = ReceiveCli::try_parse_from(["receive", "--dry-run"].iter().chain(config))
+                .unwrap()
+                .args;
+            let builder = PipelineBuilder::new(&args, VideoInfo::dry_run());
+            let required = builder.required_elements();
+            if !has_elements(&required) {
+                continue;
+            }
+
+            let built = builder.build().unwrap();
+            for element in built.pipeline.iterate_recurse().into_iter() {
+                let element = element.unwrap();
+                if element.is::<gst::Bin>() {
+                    continue;
+                }
+
+                let factory = element.factory().unwrap();
+                assert!(
+                    required.contains(&factory.name().as_str()),
+                    "{} is built but not required with {:?}",
+                    factory.name(),
+                    config
+                );
+            }
+
+            if args.no_audio_filters {
+                assert!(!required.contains(&"audiocheblimit"));
+                assert!(!required.contains(&"equalizer-10bands"));
+            }
+        }
+
+        let _ = std::fs::remove_dir_all(&hls_dir);
+    }
+}
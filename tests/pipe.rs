@@ -0,0 +1,232 @@
+//! Run a producer and a receiver wired together like qrexec does, on test sources
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gstreamer as gst;
+use gstreamer::prelude::{Cast, ElementExt, ElementExtManual, GstBinExt, ObjectExt, PadExtManual};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+
+const BIN: &str = env!("CARGO_BIN_EXE_qubes-streaming");
+
+/// Elements both ends need to run on test sources and record to FLV,
+/// besides the H.264 encoder
+const ELEMENTS: [&str; 12] = [
+    "videotestsrc",
+    "audiotestsrc",
+    "fdsrc",
+    "rawvideoparse",
+    "videorate",
+    "videoconvert",
+    "audioconvert",
+    "audioresample",
+    "fdkaacenc",
+    "h264parse",
+    "flvmux",
+    "splitmuxsink",
+];
+
+/// Set to skip the tests whose GStreamer elements are missing, rather than fail them
+const SKIP_MISSING_ENV: &str = "QUBES_STREAMING_SKIP_MISSING_PLUGINS";
+
+/// Whether GStreamer has the elements to run a session, along with `extra`.
+/// Missing ones fail the test, unless skipping is asked for
+fn has_elements(extra: &[&str]) -> bool {
+    gst::init().unwrap();
+
+    let mut missing = ELEMENTS
+        .iter()
+        .chain(extra)
+        .copied()
+        .filter(|name| gst::ElementFactory::find(name).is_none())
+        .collect::<Vec<_>>();
+
+    let has_encoder = ["nvh264enc", "openh264enc"]
+        .iter()
+        .any(|name| gst::ElementFactory::find(name).is_some());
+    if !has_encoder {
+        missing.push("openh264enc");
+    }
+
+    if missing.is_empty() {
+        return true;
+    }
+
+    assert!(
+        std::env::var_os(SKIP_MISSING_ENV).is_some(),
+        "missing GStreamer elements {:?}, set {} to skip the tests needing them",
+        missing,
+        SKIP_MISSING_ENV
+    );
+    eprintln!("skipping, missing GStreamer elements {:?}", missing);
+
+    false
+}
+
+fn wait_timeout(child: &mut Child, timeout: Duration) -> ExitStatus {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            panic!("process {} didn't exit in {:?}", child.id(), timeout);
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn recordings(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().ends_with(".stream.flv"))
+        .collect()
+}
+
+/// Stream the test sources from a producer to a receiver recording into `dir`,
+/// stop the receiver with `signal` after a few seconds and return the recording
+fn record_session(dir: &Path, signal: Signal) -> PathBuf {
+    std::fs::create_dir_all(dir).unwrap();
+
+    // control messages flow back from the receiver stdout to the producer stdin
+    let (control_reader, control_writer) = os_pipe::pipe().unwrap();
+
+    let mut producer = Command::new(BIN)
+        .args(["produce", "--test-source"])
+        .stdin(control_reader)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut receiver = Command::new(BIN)
+        .args(["receive", "--local-only", "--test-source"])
+        .current_dir(dir)
+        .stdin(producer.stdout.take().unwrap())
+        .stdout(control_writer)
+        .spawn()
+        .unwrap();
+
+    thread::sleep(Duration::from_secs(3));
+
+    kill(Pid::from_raw(receiver.id() as i32), signal).unwrap();
+
+    let receiver_status = wait_timeout(&mut receiver, Duration::from_secs(20));
+    let producer_status = wait_timeout(&mut producer, Duration::from_secs(20));
+    assert!(receiver_status.success(), "receiver {}", receiver_status);
+    assert!(producer_status.success(), "producer {}", producer_status);
+
+    let mut recordings = recordings(dir);
+    assert_eq!(recordings.len(), 1, "{:?}", recordings);
+
+    recordings.remove(0)
+}
+
+/// Demux and decode `path` to the end, returning the number of video frames
+/// and the duration the recording announces
+fn play_to_end(path: &Path) -> (u64, Option<gst::ClockTime>) {
+    let pipeline = gst::parse::launch(
+        "filesrc name=src ! flvdemux name=demux \
+         demux.video ! queue ! h264parse ! avdec_h264 ! fakesink name=video \
+         demux.audio ! queue ! fakesink",
+    )
+    .unwrap()
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+
+    pipeline
+        .by_name("src")
+        .unwrap()
+        .set_property("location", path.to_str().unwrap());
+
+    let frames = Arc::new(AtomicU64::new(0));
+    pipeline
+        .by_name("video")
+        .unwrap()
+        .static_pad("sink")
+        .unwrap()
+        .add_probe(gst::PadProbeType::BUFFER, {
+            let frames = frames.clone();
+
+            move |_, _| {
+                frames.fetch_add(1, Ordering::Relaxed);
+                gst::PadProbeReturn::Ok
+            }
+        });
+
+    pipeline.set_state(gst::State::Playing).unwrap();
+    let msg = pipeline
+        .bus()
+        .unwrap()
+        .timed_pop_filtered(
+            gst::ClockTime::from_seconds(20),
+            &[gst::MessageType::Eos, gst::MessageType::Error],
+        )
+        .expect("recording didn't play to the end");
+    let duration = pipeline.query_duration::<gst::ClockTime>();
+    pipeline.set_state(gst::State::Null).unwrap();
+
+    if let gst::MessageView::Error(err) = msg.view() {
+        panic!("playing {}: {}", path.display(), err.error());
+    }
+
+    (frames.load(Ordering::Relaxed), duration)
+}
+
+#[test]
+fn producer_to_receiver_over_pipes() {
+    if !has_elements(&[]) {
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("qubes-streaming-pipe-{}", std::process::id()));
+    let recording = std::fs::read(record_session(&dir, Signal::SIGINT)).unwrap();
+
+    assert!(recording.starts_with(b"FLV"));
+    assert!(
+        recording.len() > 1024,
+        "recording only {} bytes",
+        recording.len()
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn terminated_recording_plays_to_the_end() {
+    if !has_elements(&["flvdemux", "avdec_h264", "fakesink"]) {
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("qubes-streaming-eos-{}", std::process::id()));
+    let recording = record_session(&dir, Signal::SIGTERM);
+
+    let (frames, duration) = play_to_end(&recording);
+    let duration = duration.expect("recording without a duration");
+
+    // 3s at 25fps, minus the startup
+    assert!(
+        duration >= gst::ClockTime::from_mseconds(2400),
+        "only {} recorded",
+        duration
+    );
+    // a truncated tail loses frames the announced duration still counts
+    let recorded = duration.mseconds() * 25 / 1000;
+    assert!(
+        frames + 2 >= recorded,
+        "{} frames played out of {} recorded",
+        frames,
+        recorded
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}